@@ -1,15 +1,33 @@
 use blockchain::models::{
-    p2p::{Event, P2P},
-    TOPIC,
+    blockchain::{Blockchain, ChainConfig},
+    gossip::BlockMessage,
+    miner::Miner,
+    p2p::{Event, NetworkConfig, P2P},
+    wallet::Wallet,
 };
-use libp2p::PeerId;
+use libp2p::{
+    identity::{ed25519, Keypair},
+    PeerId,
+};
+use speedy::Writable;
 use tokio::spawn;
 
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
 
-    let mut p2p = P2P::new().await;
+    let chain = Blockchain::new("data", ChainConfig::default())
+        .await
+        .expect("to open the blockchain");
+
+    let mut p2p = P2P::new(
+        chain,
+        Miner::default(),
+        Wallet::new(ed25519::Keypair::generate()),
+        Keypair::generate_ed25519(),
+        NetworkConfig::default(),
+    )
+    .await;
 
     // let daemon_handle = spawn(async move {
     //     p2p.daemon().await;
@@ -27,11 +45,13 @@ async fn main() {
         //     };
         // }
 
+        let message = BlockMessage::Block(p2p.chain.latest().clone());
+
         if let Err(e) = p2p
             .swarm
             .behaviour_mut()
             .gossipsub
-            .publish(TOPIC.clone(), *b"sending this message from the client bro.")
+            .publish(p2p.topics.blocks.clone(), message.write_to_vec().unwrap())
         {
             println!("Publish error: {:?}", e);
         }
@@ -39,7 +59,7 @@ async fn main() {
         if let Err(e) = p2p.s.send(Event::Liebe) {
             println!(
                 "Failed to send event to the network that the block was mined. Reason: {}",
-                e.to_string()
+                e
             );
         };
     });
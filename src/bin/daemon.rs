@@ -1,13 +1,130 @@
-use blockchain::models::p2p::P2P;
-use pretty_env_logger;
+use blockchain::models::{
+    blockchain::Blockchain,
+    genesis::GenesisSpec,
+    miner::Miner,
+    node_key,
+    p2p::{self, NetworkConfig, P2P},
+    reputation::Reputation,
+    transaction,
+    wallet::Wallet,
+};
+use clap::Parser;
+use libp2p::Multiaddr;
+use std::path::PathBuf;
 use tokio::spawn;
 
+// the key that signs our transactions, inside the data directory.
+const WALLET_FILE: &str = "wallet.key";
+// the key of the node, that its PeerId derives from, inside the data directory.
+const NODE_KEY_FILE: &str = "node.pk8";
+// the peers that are banned, inside the data directory.
+const BANS_FILE: &str = "bans.dat";
+
+#[derive(Parser)]
+struct Args {
+    /// Multiaddr of a peer to dial, e.g. /ip4/127.0.0.1/tcp/[port]
+    dial: Option<Multiaddr>,
+    /// Directory where the chain is stored.
+    #[arg(long, default_value = "data")]
+    data_dir: PathBuf,
+    /// Genesis spec of the network, in toml.
+    /// A default development network is used when missing.
+    #[arg(long)]
+    genesis: Option<PathBuf>,
+    /// Number of threads used to mine blocks.
+    /// Defaults to the number of available cores.
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Address that the rewards of the mined blocks are paid to.
+    /// Defaults to the address of the wallet of the node.
+    #[arg(long)]
+    miner_address: Option<String>,
+    /// PKCS#8 file of the ed25519 or RSA key of the node, generated
+    /// if missing. Defaults to node.pk8 inside the data directory.
+    #[arg(long)]
+    node_key: Option<PathBuf>,
+    /// Multiaddr of a bootstrap peer, ending with its PeerId, e.g.
+    /// /ip4/127.0.0.1/tcp/[port]/p2p/[peer id]. Can be repeated.
+    #[arg(long = "bootnode")]
+    bootnodes: Vec<Multiaddr>,
+    /// Number of peers to stay connected to, dialing the ones found by kademlia.
+    #[arg(long)]
+    target_peers: Option<usize>,
+    /// Discover the peers of the local network with mDNS.
+    #[arg(long)]
+    mdns: bool,
+}
+
 #[tokio::main]
 async fn main() {
     // RUST_LOG=info cargo run
     pretty_env_logger::init();
 
-    let mut p2p = P2P::new().await;
+    let args = Args::parse();
+
+    if let Some(address) = &args.miner_address {
+        assert!(
+            transaction::is_valid_address(address),
+            "invalid miner address: {address}"
+        );
+    }
+    for address in &args.bootnodes {
+        assert!(
+            p2p::peer_id_of(address).is_some(),
+            "bootnode without a PeerId: {address}"
+        );
+    }
+
+    let genesis = match &args.genesis {
+        Some(path) => GenesisSpec::load(path)
+            .await
+            .expect("to load the genesis spec"),
+        None => GenesisSpec::default(),
+    };
+
+    let chain = Blockchain::new(&args.data_dir, genesis.into())
+        .await
+        .expect("to open the blockchain");
+
+    let miner = match args.threads {
+        Some(threads) => Miner::new(threads),
+        None => Miner::default(),
+    };
+
+    let wallet = Wallet::load_or_generate(args.data_dir.join(WALLET_FILE))
+        .await
+        .expect("to load the wallet");
+
+    let node_key = args
+        .node_key
+        .unwrap_or_else(|| args.data_dir.join(NODE_KEY_FILE));
+    let keypair = node_key::load_or_generate(node_key)
+        .await
+        .expect("to load the node key");
+
+    let mut network = NetworkConfig {
+        bootnodes: args.bootnodes,
+        mdns: args.mdns,
+        ..Default::default()
+    };
+    if let Some(target_peers) = args.target_peers {
+        network.target_peers = target_peers;
+    }
+
+    let mut p2p = P2P::new(chain, miner, wallet, keypair, network).await;
+    p2p.reputation = Reputation::load(args.data_dir.join(BANS_FILE))
+        .await
+        .expect("to load the banned peers");
+    if let Some(address) = args.miner_address {
+        p2p.miner_address = address;
+    }
+
+    // Dial the peer identified by the multi-address given as the
+    // command-line argument, if any.
+    if let Some(addr) = args.dial {
+        p2p.swarm.dial(addr.clone()).unwrap();
+        println!("Dialed {}", addr);
+    }
 
     let daemon_handle = spawn(async move {
         p2p.daemon().await;
@@ -0,0 +1,231 @@
+use super::{
+    block::Block,
+    error::{ChainError, Result},
+    merkle,
+};
+use speedy::{Readable, Writable};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Writable, Readable)]
+pub struct Account {
+    pub balance: u64,
+    // number of transactions sent by the account,
+    // the next one must have exactly this nonce.
+    pub nonce: u64,
+}
+
+// An account touched by a block, before and after it.
+#[derive(Debug, Clone, Writable, Readable)]
+pub struct AccountChange {
+    pub address: String,
+    pub before: Option<Account>,
+    pub after: Account,
+}
+
+#[derive(Debug, Clone, Default, Writable, Readable)]
+pub struct AccountDiff {
+    // hash of the block.
+    pub hash: String,
+    pub changes: Vec<AccountChange>,
+}
+
+// Balances and nonces of every account that ever received coins.
+#[derive(Debug, Clone, Default)]
+pub struct AccountState {
+    // a BTreeMap iterates in a deterministic order, for the state root.
+    accounts: BTreeMap<String, Account>,
+}
+
+impl AccountState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+    pub fn get(&self, address: &str) -> Account {
+        self.accounts.get(address).copied().unwrap_or_default()
+    }
+    // Check the transactions of `block` against the state and compute
+    // its diff, without changing the state. Transactions are applied in
+    // order, each one sees the changes of the ones before it.
+    pub fn diff(&self, block: &Block) -> Result<AccountDiff> {
+        let height = block.header.id;
+        let mut touched: BTreeMap<String, (Option<Account>, Account)> = BTreeMap::new();
+
+        for (index, tx) in block.transactions.iter().enumerate() {
+            let hash = tx.hash();
+            let value = tx
+                .output_value()
+                .ok_or_else(|| ChainError::UnbalancedTransaction {
+                    height,
+                    tx: hash.clone(),
+                })?;
+
+            if !block.mints(index) {
+                if !tx.inputs.is_empty() {
+                    return Err(ChainError::InvalidSpend { height, tx: hash });
+                }
+                let sender = self.touch(&mut touched, tx.sender_address());
+
+                if tx.nonce != sender.nonce {
+                    return Err(ChainError::InvalidNonce {
+                        height,
+                        tx: hash,
+                        expected: sender.nonce,
+                        found: tx.nonce,
+                    });
+                }
+                sender.balance = value
+                    .checked_add(tx.fee)
+                    .and_then(|spent| sender.balance.checked_sub(spent))
+                    .ok_or_else(|| ChainError::UnbalancedTransaction {
+                        height,
+                        tx: hash.clone(),
+                    })?;
+                sender.nonce += 1;
+            }
+
+            for output in &tx.outputs {
+                let recipient = self.touch(&mut touched, output.recipient.clone());
+
+                // minted coins can push a balance past what fits.
+                recipient.balance =
+                    recipient
+                        .balance
+                        .checked_add(output.amount)
+                        .ok_or_else(|| ChainError::UnbalancedTransaction {
+                            height,
+                            tx: hash.clone(),
+                        })?;
+            }
+        }
+
+        Ok(AccountDiff {
+            hash: block.hash.clone(),
+            changes: touched
+                .into_iter()
+                .map(|(address, (before, after))| AccountChange {
+                    address,
+                    before,
+                    after,
+                })
+                .collect(),
+        })
+    }
+    pub fn apply(&mut self, diff: &AccountDiff) {
+        for change in &diff.changes {
+            self.accounts.insert(change.address.clone(), change.after);
+        }
+    }
+    pub fn revert(&mut self, diff: &AccountDiff) {
+        for change in &diff.changes {
+            match change.before {
+                Some(account) => self.accounts.insert(change.address.clone(), account),
+                None => self.accounts.remove(&change.address),
+            };
+        }
+    }
+    // Merkle root of every account, in the order of their addresses.
+    pub fn root(&self) -> String {
+        let leaves: Vec<_> = self
+            .accounts
+            .iter()
+            .map(|(address, account)| {
+                let leaf = (address, account.balance, account.nonce);
+                merkle::hash_leaf(&leaf.write_to_vec().unwrap())
+            })
+            .collect();
+
+        hex::encode(merkle::root(&leaves))
+    }
+    // The root of the state once `diff` is applied.
+    pub fn root_after(&self, diff: &AccountDiff) -> String {
+        let mut state = self.clone();
+        state.apply(diff);
+        state.root()
+    }
+    // The account of `address` in the diff being built.
+    fn touch<'a>(
+        &self,
+        touched: &'a mut BTreeMap<String, (Option<Account>, Account)>,
+        address: String,
+    ) -> &'a mut Account {
+        let before = self.accounts.get(&address).copied();
+        &mut touched
+            .entry(address)
+            .or_insert_with(|| (before, before.unwrap_or_default()))
+            .1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::transaction::{self, Output, Transaction};
+    use libp2p::identity::ed25519::Keypair;
+
+    fn block(id: u64, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(id, String::new(), transactions, 0x2000ffff);
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    #[test]
+    fn balance_overflow_is_refused() {
+        let address = "aa".repeat(32);
+        let mut state = AccountState::new();
+        let genesis = block(0, vec![Transaction::mint(address.clone(), u64::MAX)]);
+        state.apply(&state.diff(&genesis).unwrap());
+
+        let coinbase = Transaction::coinbase(1, address.clone(), 1);
+        assert!(matches!(
+            state.diff(&block(1, vec![coinbase])),
+            Err(ChainError::UnbalancedTransaction { height: 1, .. })
+        ));
+        assert_eq!(state.get(&address).balance, u64::MAX);
+    }
+
+    #[test]
+    fn transfer_checks_nonce_and_balance() {
+        let alice = Keypair::generate();
+        let sender = transaction::address(&alice.public());
+        let bob = "bb".repeat(32);
+        let mut state = AccountState::new();
+        let genesis = block(0, vec![Transaction::mint(sender.clone(), 100)]);
+        state.apply(&state.diff(&genesis).unwrap());
+
+        let pay = |amount, nonce| {
+            let output = Output {
+                recipient: bob.clone(),
+                amount,
+            };
+            Transaction::new(&alice, Vec::new(), vec![output], nonce, 1)
+        };
+        let coinbase = Transaction::coinbase(1, "aa".repeat(32), 50);
+
+        let diff = state
+            .diff(&block(1, vec![coinbase.clone(), pay(60, 0), pay(30, 1)]))
+            .unwrap();
+        state.apply(&diff);
+        assert_eq!(state.get(&sender).balance, 8);
+        assert_eq!(state.get(&sender).nonce, 2);
+        assert_eq!(state.get(&bob).balance, 90);
+
+        // a replayed nonce, and more than the balance.
+        let replay = block(2, vec![coinbase.clone(), pay(1, 1)]);
+        assert!(matches!(
+            state.diff(&replay),
+            Err(ChainError::InvalidNonce { expected: 2, .. })
+        ));
+        let broke = block(2, vec![coinbase, pay(8, 2)]);
+        assert!(state.diff(&broke).is_err());
+
+        state.revert(&diff);
+        assert_eq!(state.get(&sender).balance, 100);
+        assert_eq!(state.get(&bob), Account::default());
+    }
+}
@@ -1,117 +1,310 @@
-use super::blockchain;
+use super::{
+    blockchain::ChainConfig,
+    error::{ChainError, Result},
+    merkle::{self, Hash, MerkleProof},
+    pow,
+    state::Ledger,
+    transaction::Transaction,
+};
 use chrono::prelude::*;
 use log::{debug, info, warn};
-use once_cell::sync::Lazy;
 use sha2::{Digest, Sha256};
 use speedy::{Readable, Writable};
 use tokio::time::Instant;
 
-static DIFFICULTY: Lazy<usize> = Lazy::new(|| 4);
+// how far in the future, in milliseconds, the timestamp of a block can be
+// compared to our clock, to tolerate clock drift between nodes.
+const MAX_FUTURE_DRIFT: u64 = 2 * 60 * 60 * 1000;
 
-#[derive(Debug, Clone, Writable, Readable)]
-pub struct Block {
+// Everything that the proof of work commits to. The transactions are
+// committed through the merkle root, so the cost of hashing a header
+// doesn't depend on the size of the block.
+#[derive(Debug, Clone, PartialEq, Eq, Writable, Readable)]
+pub struct BlockHeader {
     pub id: u64,
-    pub hash: String,
     pub previous_hash: String,
     pub timestamp: u64,
-    pub data: String,
     pub nonce: u64,
+    // compact form of the target that the hash must meet.
+    pub bits: u32,
+    // hex merkle root of the transactions of the block.
+    pub merkle_root: String,
+    // hex merkle root of the accounts after the block,
+    // empty for chains with the utxo model.
+    pub state_root: String,
+}
+
+impl BlockHeader {
+    pub fn calculate_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.write_to_vec().unwrap());
+
+        format!("{:x}", hasher.finalize())
+    }
+    // If the hash of the header meets its own target, without the body
+    // and without checking that the target is the right one.
+    pub fn meets_target(&self) -> bool {
+        match pow::hash_to_u256(&self.calculate_hash()) {
+            Some(hash) => hash <= pow::from_compact(self.bits),
+            None => false,
+        }
+    }
+    // If the timestamp is not before the one of `previous`,
+    // and not too far ahead of our clock.
+    pub fn has_valid_timestamp(&self, previous: &BlockHeader) -> bool {
+        let now = Utc::now().timestamp_millis() as u64;
+
+        self.timestamp >= previous.timestamp && self.timestamp <= now + MAX_FUTURE_DRIFT
+    }
+}
+
+#[derive(Debug, Clone, Writable, Readable)]
+pub struct Block {
+    pub header: BlockHeader,
+    // hash of the header.
+    pub hash: String,
+    pub transactions: Vec<Transaction>,
 }
 
 impl Block {
-    // Create a new block. The hash will be calculated and set automatically.
-    pub fn new(id: u64, previous_hash: String, data: String) -> Self {
+    // Create a new block, its hash is set once it is mined,
+    // and its state root must be set for the account model.
+    pub fn new(id: u64, previous_hash: String, transactions: Vec<Transaction>, bits: u32) -> Self {
         Block {
-            id,
+            header: BlockHeader {
+                id,
+                previous_hash,
+                timestamp: Utc::now().timestamp_millis() as u64,
+                nonce: u64::default(),
+                bits,
+                merkle_root: merkle_root(&transactions),
+                state_root: String::new(),
+            },
             hash: String::default(),
-            previous_hash,
-            timestamp: Utc::now().timestamp_millis() as u64,
-            data,
-            nonce: u64::default(),
+            transactions,
         }
     }
     pub fn calculate_hash(&self) -> String {
-        let mut block_data = self.clone();
-        block_data.hash = String::default();
-
-        let serialized_block_data = block_data.write_to_vec().unwrap();
-
-        let mut hasher = Sha256::new();
-        hasher.update(serialized_block_data);
-
-        let result = hasher.finalize();
-
-        format!("{:x}", result)
+        self.header.calculate_hash()
+    }
+    // If the hash is lower or equal to the target of the block.
+    pub fn meets_target(&self) -> bool {
+        match pow::hash_to_u256(&self.hash) {
+            Some(hash) => hash <= pow::from_compact(self.header.bits),
+            None => false,
+        }
     }
+    // Increment the nonce until the hash meets the target.
     pub fn mine(&mut self) {
         let now = Instant::now();
         loop {
-            if !self.hash.starts_with(&"0".repeat(*DIFFICULTY)) {
-                self.nonce += 1;
+            if !self.meets_target() {
+                self.header.nonce += 1;
                 self.hash = self.calculate_hash();
             } else {
                 info!(
                     "block mined in {}s with nonce: \"{}\"",
                     now.elapsed().as_secs(),
-                    self.nonce
+                    self.header.nonce
                 );
                 break;
             }
         }
     }
-    pub async fn validate(&self) -> Result<(), String> {
-        let previous_block = blockchain::get_latest_block()
-            .await
-            .map_err(|e| e.to_string())?;
+    // Check that the hash matches the header, and meets the target of
+    // the block. This doesn't need any other block, but doesn't check
+    // if the target itself is the right one.
+    pub fn check_pow(&self) -> Result<()> {
+        if self.hash != self.calculate_hash() {
+            warn!("block with id: {} has a tampered hash.", self.header.id);
+            return Err(ChainError::InvalidHash {
+                height: self.header.id,
+            });
+        }
+        if !self.meets_target() {
+            warn!("block with id: {} was not mined.", self.header.id);
+            return Err(ChainError::InvalidPow {
+                height: self.header.id,
+            });
+        }
+        Ok(())
+    }
+    // Check the proof of work, and that the header commits
+    // to the transactions that come with it.
+    pub fn check(&self) -> Result<()> {
+        self.check_pow()?;
+
+        if self.header.merkle_root != merkle_root(&self.transactions) {
+            warn!(
+                "block with id: {} has tampered transactions.",
+                self.header.id
+            );
+            return Err(ChainError::InvalidMerkleRoot {
+                height: self.header.id,
+            });
+        }
+        Ok(())
+    }
+    // If the transaction at `index` creates coins: every transaction
+    // of the genesis block, and the coinbase of the other blocks.
+    pub fn mints(&self, index: usize) -> bool {
+        match self.transactions.get(index) {
+            Some(tx) => tx.is_mint() && (self.header.id == 0 || index == 0),
+            None => false,
+        }
+    }
+    // Validate this block against the block that comes right before it,
+    // and the ledger right after it. `bits` is the target in force at
+    // the height of this block, see `PowParams::next_bits`, and `subsidy`
+    // the new coins it can mint, see `RewardParams::subsidy`.
+    pub fn validate(
+        &self,
+        previous_block: &Block,
+        bits: u32,
+        subsidy: u64,
+        ledger: &Ledger,
+    ) -> Result<()> {
+        self.validate_without_state(previous_block, bits, subsidy)?;
+        ledger.diff(self)?;
 
+        Ok(())
+    }
+    // Everything that `validate` checks but the transitions of the ledger, which need
+    // the state of the chain right before this block.
+    pub fn validate_without_state(
+        &self,
+        previous_block: &Block,
+        bits: u32,
+        subsidy: u64,
+    ) -> Result<()> {
         debug!("-- validating new block --");
         debug!("prev {:#?}", previous_block);
         debug!("curent to be added {:#?}", self);
 
-        if self.previous_hash != previous_block.hash {
-            warn!("block with id: {} passed invalid previous_hash.", self.id);
-            return Err("block passed invalid previous_hash.".to_string());
+        let header = &self.header;
+
+        if header.previous_hash != previous_block.hash {
+            warn!("block with id: {} passed invalid previous_hash.", header.id);
+            return Err(ChainError::InvalidPreviousHash { height: header.id });
         }
-        if self.id != previous_block.id + 1 {
-            warn!("invalid block id: {}", self.id);
-            return Err("invalid block id.".to_string());
+        if header.id != previous_block.header.id + 1 {
+            warn!("invalid block id: {}", header.id);
+            return Err(ChainError::InvalidHeight {
+                height: header.id,
+                expected: previous_block.header.id + 1,
+            });
         }
-        info!("valid block, beginning to mine now...");
-        return Ok(());
+        if header.bits != bits {
+            warn!("block with id: {} has an invalid target.", header.id);
+            return Err(ChainError::InvalidTarget {
+                height: header.id,
+                expected: bits,
+                found: header.bits,
+            });
+        }
+        self.check()?;
+
+        if !header.has_valid_timestamp(&previous_block.header) {
+            warn!("block with id: {} has an invalid timestamp.", header.id);
+            return Err(ChainError::InvalidTimestamp { height: header.id });
+        }
+        self.check_coinbase(subsidy)?;
+
+        // the coinbase is the only one that mints coins, the genesis
+        // block is never validated.
+        if let Some(tx) = self.transactions[1..].iter().find(|tx| !tx.verify()) {
+            warn!("block with id: {} has an invalid transaction.", header.id);
+            return Err(ChainError::InvalidSignature {
+                height: header.id,
+                tx: tx.hash(),
+            });
+        }
+        debug!("block with id: {} is valid", header.id);
+        Ok(())
     }
-    pub fn validate_all(blocks: &Vec<Block>) -> Result<(), String> {
-        for i in 0..blocks.len() {
-            // genesis block cant be validated
-            if i == 0 {
-                continue;
-            };
-
-            let current_block = blocks.get(i);
-
-            let previous_block = blocks
-                .get((current_block.unwrap().id - 1 as u64) as usize)
-                .unwrap()
-                .to_owned();
-
-            // println!("current {:#?}", current_block.unwrap());
-            // println!("previous {:#?}", previous_block);
-
-            if let Some(current_block) = current_block {
-                if current_block.previous_hash != previous_block.hash {
-                    warn!(
-                        "block with id: {} passed invalid previous_hash.",
-                        current_block.id
-                    );
-                    return Err("block passed invalid previous_hash.".to_string());
-                }
-                if current_block.id != previous_block.id + 1 {
-                    warn!("invalid block id: {}", current_block.id);
-                    return Err("invalid block id.".to_string());
-                }
-            } else {
-                return Err("Could not get block with id {i}".to_string());
+    // The block must start with a coinbase that pays exactly `subsidy`
+    // plus the fees of the other transactions of the block.
+    fn check_coinbase(&self, subsidy: u64) -> Result<()> {
+        let height = self.header.id;
+
+        let coinbase = match self.transactions.first() {
+            Some(tx) if tx.is_mint() && tx.nonce == height && tx.fee == 0 => tx,
+            _ => {
+                warn!("block with id: {} has no valid coinbase.", height);
+                return Err(ChainError::InvalidCoinbase { height });
+            }
+        };
+        let expected = self.transactions[1..]
+            .iter()
+            .try_fold(subsidy, |sum, tx| sum.checked_add(tx.fee));
+
+        match (expected, coinbase.output_value()) {
+            (Some(expected), Some(found)) if expected == found => Ok(()),
+            (expected, found) => {
+                warn!("block with id: {} pays an invalid reward.", height);
+                Err(ChainError::InvalidReward {
+                    height,
+                    expected: expected.unwrap_or(u64::MAX),
+                    found: found.unwrap_or(u64::MAX),
+                })
             }
         }
+    }
+    // Validate a whole chain, starting from the genesis block of `config`.
+    pub fn validate_all(blocks: &[Block], config: &ChainConfig) -> Result<()> {
+        let params = &config.pow;
+        let mut ledger = Ledger::new(config.ledger);
+        if let Some(first) = blocks.first() {
+            let genesis = config.genesis.block();
+            if first.hash != genesis.hash {
+                return Err(ChainError::GenesisMismatch {
+                    expected: genesis.hash,
+                    found: first.hash.clone(),
+                });
+            }
+            ledger.apply(&ledger.diff(first)?);
+        }
+
+        // genesis block cant be validated
+        for (i, pair) in blocks.windows(2).enumerate() {
+            let height = i as u64 + 1;
+            let window_start = blocks.get(height.saturating_sub(params.retarget_interval) as usize);
+            let bits = params.next_bits(&pair[0].header, window_start.map(|block| &block.header));
+
+            let subsidy = config.reward.subsidy(height);
+
+            pair[1].validate_without_state(&pair[0], bits, subsidy)?;
+            ledger.apply(&ledger.diff(&pair[1])?);
+        }
         Ok(())
     }
 }
+
+// hex merkle root of `transactions`, every leaf is a speedy encoded transaction.
+pub fn merkle_root(transactions: &[Transaction]) -> String {
+    let leaves: Vec<_> = transactions.iter().map(leaf).collect();
+
+    hex::encode(merkle::root(&leaves))
+}
+
+// Proof that the transaction at `index` is committed by the merkle root.
+pub fn merkle_proof(transactions: &[Transaction], index: usize) -> Option<MerkleProof> {
+    let leaves: Vec<_> = transactions.iter().map(leaf).collect();
+
+    merkle::proof(&leaves, index)
+}
+
+// If `entry` is one of the transactions of the block of `header`. Only
+// the header is needed, which is enough for a light client that trusts
+// the header, for instance because it checked its proof of work and
+// that it is part of the chain.
+pub fn verify_inclusion(header: &BlockHeader, entry: &Transaction, proof: &MerkleProof) -> bool {
+    match merkle::decode_hash(&header.merkle_root) {
+        Some(root) => merkle::verify(&root, &leaf(entry), proof),
+        None => false,
+    }
+}
+
+fn leaf(tx: &Transaction) -> Hash {
+    merkle::hash_leaf(&tx.write_to_vec().unwrap())
+}
@@ -1,166 +1,1051 @@
-use super::{block::Block, p2p::Event};
-use chrono::prelude::*;
-use log::{debug, error, info, warn};
-use speedy::{Readable, Writable};
-use tokio::{
-    fs::{File, OpenOptions},
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    time::Instant,
+use super::{
+    block::{self, Block, BlockHeader},
+    error::{ChainError, Result},
+    genesis::GenesisSpec,
+    merkle::MerkleProof,
+    pow::{self, PowParams, U256},
+    reward::RewardParams,
+    state::{Ledger, LedgerKind, StateDiff, StateStore},
+    store::BlockStore,
+    transaction::Transaction,
+    tree::BlockTree,
 };
+use futures::{stream, Stream, StreamExt};
+use log::{debug, info, warn};
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+};
+use tokio::{sync::broadcast, time::Instant};
+
+// directory of the append-only block log, inside the data directory.
+const BLOCKS_DIR: &str = "blocks";
+// journal of the ledger, inside the data directory.
+const STATE_FILE: &str = "state.dat";
+// side branches that fork deeper than this below the tip are forgotten.
+const MAX_FORK_DEPTH: u64 = 100;
+// events that a slow subscriber can lag behind before losing some.
+const EVENTS_CAPACITY: usize = 1024;
 
-// #[derive(Clone)]
-// pub struct Blockchain {
-//     pub difficulty: usize,
-// }
-
-pub async fn open() -> File {
-    OpenOptions::new()
-        .write(true)
-        .read(true)
-        .create(true)
-        .open("blockchain")
-        .await
-        .expect("to open blockchain file")
+// Changes of the canonical chain, in the order they are applied.
+// On a reorg, the blocks of the old branch are disconnected from the old
+// tip down to the fork point, then the blocks of the new branch are
+// connected from the fork point up to the new tip.
+#[derive(Debug, Clone)]
+pub enum ChainEvent {
+    BlockConnected(Block),
+    BlockDisconnected(Block),
 }
-// pub async fn new(difficulty: usize) -> io::Result<Self> {
-//     let mut buf = Vec::new();
-//     let mut chain: Vec<Block> = Vec::new();
-
-//     let mut file = self::open().await;
-
-//     file.read_to_end(&mut buf).await?;
-
-//     if buf.len() == 0 {
-//         let genesis = Block {
-//             id: 0,
-//             timestamp: Utc::now().timestamp_millis() as u64,
-//             nonce: u64::default(),
-//             previous_hash: String::default(),
-//             hash: "0".to_string(),
-//             data: "Genesis".to_string(),
-//         };
-//         // Create chain starting from the genesis chain.
-//         chain.push(genesis.clone());
-
-//         // Write the data into the blockchain file.
-//         let chain_bytes = chain.write_to_vec()?;
-//         file.write_all(&chain_bytes[..]).await?;
-//     }
-
-//     Ok(())
-// }
-pub async fn read_all_buf() -> io::Result<Vec<u8>> {
-    let mut buf: Vec<u8> = Vec::new();
-    open().await.read_to_end(&mut buf).await?;
-    // println!("read_all_buf buffer: {:?}", buf);
-
-    Ok(buf)
+
+// What happened to a block given to `Blockchain::process_block`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    // the block was already known.
+    Duplicate,
+    // the parent of the block is unknown, it waits in the orphan pool.
+    Orphan,
+    // the block is valid, but on a branch with less work than the tip.
+    SideBranch,
+    // the block is the new tip, possibly after a reorg.
+    Connected,
 }
-pub async fn read_all() -> io::Result<Vec<Block>> {
-    let now = Instant::now();
-    let buf: Vec<u8> = read_all_buf().await.expect("read all buf");
-    let chain = Vec::<Block>::read_from_buffer(&buf[..]).expect("to read from buffer");
-    info!(
-        "took {}μs to read the blockchain.",
-        now.elapsed().as_micros()
-    );
-
-    Ok(chain)
+
+#[derive(Debug, Clone)]
+pub struct ChainConfig {
+    pub pow: PowParams,
+    pub reward: RewardParams,
+    pub ledger: LedgerKind,
+    pub genesis: GenesisSpec,
+}
+
+impl From<GenesisSpec> for ChainConfig {
+    fn from(genesis: GenesisSpec) -> Self {
+        Self {
+            pow: genesis.pow.clone(),
+            reward: genesis.reward.clone(),
+            ledger: genesis.ledger,
+            genesis,
+        }
+    }
+}
+
+impl Default for ChainConfig {
+    fn default() -> Self {
+        GenesisSpec::default().into()
+    }
+}
+
+// An owned handle to a chain stored in a data directory.
+// The chain always has at least the genesis block.
+pub struct Blockchain {
+    dir: PathBuf,
+    config: ChainConfig,
+    store: BlockStore,
+    state: StateStore,
+    tree: BlockTree,
+    events: broadcast::Sender<ChainEvent>,
+    genesis_hash: String,
+    tip: Block,
 }
-// a block will only be pushed to the blockchain,
-// once it has been validated and mined.
-pub async fn add_block(data: String, s: UnboundedSender<Event>) {
-    let mut blockchain = read_all()
-        .await
-        .expect("to read blockchain before add block");
-
-    debug!("\n blockchain before mining new block: {:#?}", blockchain);
-    info!(
-        "Received block with id \"{}\" and data: \"{}\"",
-        blockchain.len(),
-        data
-    );
-
-    let mut new_block = Block::new(
-        blockchain.len() as u64,
-        blockchain.last().unwrap().hash.clone(),
-        data,
-    );
-
-    match new_block.validate().await {
-        Ok(_) => {
-            new_block.mine();
-
-            debug!("block to be added is valid");
-
-            blockchain.push(new_block.clone());
-
-            let chain = blockchain.write_to_vec().unwrap();
-
-            if let Err(e) = s.send(Event::BlockMined(chain)) {
-                error!(
-                    "Failed to send event to the network that the block was mined. Reason: {}",
-                    e.to_string()
+
+impl Blockchain {
+    // Open the chain in `dir`, creating it with the genesis block
+    // of `config` if it is empty. A chain that was created from
+    // another genesis spec is refused.
+    pub async fn new(dir: impl AsRef<Path>, config: ChainConfig) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        let mut store = BlockStore::open(dir.join(BLOCKS_DIR)).await?;
+        let genesis = config.genesis.block();
+
+        match store.get_block_by_height(0).await? {
+            Some(block) if block.hash != genesis.hash => {
+                return Err(ChainError::GenesisMismatch {
+                    expected: genesis.hash,
+                    found: block.hash,
+                });
+            }
+            Some(_) => {}
+            None => {
+                info!(
+                    "creating genesis block of \"{}\" with hash {}",
+                    config.genesis.chain_name, genesis.hash
                 );
-            };
+                store.append(&genesis).await?;
+            }
         }
-        Err(_) => {
-            debug!("block is invalid!");
-            warn!("Could not add new block to the blockchain.");
+        let tip = store.tip().cloned().ok_or(ChainError::EmptyChain)?;
+        let state = StateStore::open(dir.join(STATE_FILE), config.ledger).await?;
+
+        let mut chain = Self {
+            dir,
+            config,
+            store,
+            state,
+            tree: BlockTree::new(),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            genesis_hash: genesis.hash,
+            tip,
+        };
+        chain.sync_state().await?;
+
+        Ok(chain)
+    }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    pub fn config(&self) -> &ChainConfig {
+        &self.config
+    }
+    // number of blocks, including the genesis block.
+    pub fn len(&self) -> u64 {
+        self.store.len()
+    }
+    // The state of the chain at the tip.
+    pub fn ledger(&self) -> &Ledger {
+        self.state.ledger()
+    }
+    // a chain is never empty, it always has the genesis block.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+    // Receive every change of the canonical chain from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChainEvent> {
+        self.events.subscribe()
+    }
+    // If the block is in the canonical chain or in a side branch.
+    pub fn contains(&self, hash: &str) -> bool {
+        self.store.height_of(hash).is_some() || self.tree.get(hash).is_some()
+    }
+    pub fn genesis_hash(&self) -> &str {
+        &self.genesis_hash
+    }
+    // Chains from peers that started from another genesis block
+    // belong to another network, and must be refused.
+    pub fn check_genesis(&self, chain: &[Block]) -> Result<()> {
+        match chain.first() {
+            Some(genesis) if genesis.hash == self.genesis_hash => Ok(()),
+            Some(genesis) => Err(ChainError::GenesisMismatch {
+                expected: self.genesis_hash.clone(),
+                found: genesis.hash.clone(),
+            }),
+            None => Err(ChainError::EmptyChain),
         }
     }
-}
-// always choose the longest chain
-pub async fn choose_chain(local: &mut Vec<u8>, remote: &mut Vec<u8>) -> Result<Vec<Block>, String> {
-    let local = Vec::<Block>::read_from_buffer(&mut local[..]).unwrap();
-    let remote = Vec::<Block>::read_from_buffer(&mut remote[..]).unwrap();
+    pub fn latest(&self) -> &Block {
+        &self.tip
+    }
+    // Accumulated proof of work of the whole chain.
+    pub fn work(&self) -> U256 {
+        self.store.work()
+    }
+    pub async fn get_block_by_height(&self, height: u64) -> Result<Option<Block>> {
+        Ok(self.store.get_block_by_height(height).await?)
+    }
+    pub async fn get_block_by_hash(&self, hash: &str) -> Result<Option<Block>> {
+        Ok(self.store.get_block_by_hash(hash).await?)
+    }
+    // Proof that the transaction at `index` of the block at `height` is part
+    // of the chain, to be checked with `block::verify_inclusion` against
+    // the header of the block.
+    pub async fn merkle_proof(&self, height: u64, index: usize) -> Result<MerkleProof> {
+        let block = self
+            .get_block_by_height(height)
+            .await?
+            .ok_or(ChainError::MissingBlock { height })?;
+
+        block::merkle_proof(&block.transactions, index)
+            .ok_or(ChainError::MissingTransaction { height, index })
+    }
+    // Hashes of the canonical chain that a peer uses to find where its chain
+    // forks from ours: the last 10 blocks, then exponentially sparser ones,
+    // down to the genesis block.
+    pub fn locator(&self) -> Vec<String> {
+        let mut locator = Vec::new();
+        let mut height = self.len() - 1;
+        let mut step = 1;
+
+        while let Some(hash) = self.store.hash_at(height) {
+            locator.push(hash.to_string());
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+
+        locator
+    }
+    // At most `limit` headers of the canonical chain, after the first block
+    // of `locator` that is part of it, empty if that block is the tip. A
+    // locator ends with the genesis block, so if none of its blocks is part
+    // of the chain, the peer is on another network.
+    pub async fn headers_after(&self, locator: &[String], limit: u64) -> Result<Vec<BlockHeader>> {
+        let fork = match locator.iter().find_map(|hash| self.store.height_of(hash)) {
+            Some(height) => height,
+            None => {
+                return Err(ChainError::GenesisMismatch {
+                    expected: self.genesis_hash.clone(),
+                    found: locator.last().cloned().unwrap_or_default(),
+                })
+            }
+        };
+        let end = self.len().min(fork + 1 + limit);
+
+        let mut headers = Vec::new();
+        for height in fork + 1..end {
+            let block = self
+                .get_block_by_height(height)
+                .await?
+                .ok_or(ChainError::MissingBlock { height })?;
+            headers.push(block.header);
+        }
+
+        Ok(headers)
+    }
+    // At most `count` blocks of the canonical chain, from height `from`.
+    pub async fn blocks_range(&self, from: u64, count: u64) -> Result<Vec<Block>> {
+        let end = self.len().min(from.saturating_add(count));
+
+        let mut blocks = Vec::new();
+        for height in from..end {
+            blocks.push(
+                self.get_block_by_height(height)
+                    .await?
+                    .ok_or(ChainError::MissingBlock { height })?,
+            );
+        }
+
+        Ok(blocks)
+    }
+    // Check `headers`, that come right after `branch`, headers that fork
+    // from the canonical chain and were already checked, or that fork from it
+    // themselves if `branch` is empty. Every header must follow the one
+    // before it, and have the right target and meet it, which only needs
+    // the headers. The bodies are validated when the blocks are connected.
+    // Returns the accumulated work of the chain that ends with `headers`.
+    pub async fn validate_headers(
+        &self,
+        branch: &[BlockHeader],
+        headers: &[BlockHeader],
+    ) -> Result<U256> {
+        let first = match branch.first().or(headers.first()) {
+            Some(first) => first,
+            None => return Ok(self.work()),
+        };
+        let fork =
+            self.store
+                .height_of(&first.previous_hash)
+                .ok_or_else(|| ChainError::UnknownBlock {
+                    hash: first.previous_hash.clone(),
+                })?;
+        self.check_fork_depth(fork + 1)?;
+        let pow = &self.config.pow;
+        let all: Vec<_> = branch.iter().chain(headers).collect();
+
+        let mut previous = match branch.last() {
+            Some(header) => header.clone(),
+            None => self.header_at(fork).await?,
+        };
+        let mut work = self.store.work_at(fork).unwrap_or_default()
+            + branch
+                .iter()
+                .fold(U256::zero(), |work, header| work + pow::work(header.bits));
+
+        for header in headers {
+            let height = header.id;
+
+            if header.previous_hash != previous.calculate_hash() {
+                return Err(ChainError::InvalidPreviousHash { height });
+            }
+            if height != previous.id + 1 {
+                return Err(ChainError::InvalidHeight {
+                    height,
+                    expected: previous.id + 1,
+                });
+            }
+            let mut window_start = None;
+            if pow.is_retarget_height(height) {
+                let start = height - pow.retarget_interval;
+                window_start = Some(match start.checked_sub(fork + 1) {
+                    Some(i) => all[i as usize].clone(),
+                    None => self.header_at(start).await?,
+                });
+            }
+            let bits = pow.next_bits(&previous, window_start.as_ref());
+            if header.bits != bits {
+                return Err(ChainError::InvalidTarget {
+                    height,
+                    expected: bits,
+                    found: header.bits,
+                });
+            }
+            if !header.meets_target() {
+                return Err(ChainError::InvalidPow { height });
+            }
+            if !header.has_valid_timestamp(&previous) {
+                return Err(ChainError::InvalidTimestamp { height });
+            }
+            work += pow::work(header.bits);
+            previous = header.clone();
+        }
+
+        Ok(work)
+    }
+    async fn header_at(&self, height: u64) -> Result<BlockHeader> {
+        self.get_block_by_height(height)
+            .await?
+            .map(|block| block.header)
+            .ok_or(ChainError::MissingBlock { height })
+    }
+    // Iterate over every block of the chain, starting from the genesis block.
+    pub fn iter(&self) -> impl Stream<Item = Result<Block>> + '_ {
+        stream::iter(0..self.len()).then(move |height| async move {
+            self.store
+                .get_block_by_height(height)
+                .await?
+                .ok_or(ChainError::MissingBlock { height })
+        })
+    }
+    pub async fn read_all(&self) -> Result<Vec<Block>> {
+        let now = Instant::now();
+        let chain = self.store.read_all().await?;
+        info!(
+            "took {}μs to read the blockchain.",
+            now.elapsed().as_micros()
+        );
+
+        Ok(chain)
+    }
+    // The target that the block after `previous` must have.
+    pub async fn next_bits(&self, previous: &Block) -> Result<u32> {
+        let height = previous.header.id + 1;
+        let mut window_start = None;
+
+        if self.config.pow.is_retarget_height(height) {
+            let start = height - self.config.pow.retarget_interval;
+            window_start = Some(
+                self.get_block_by_height(start)
+                    .await?
+                    .ok_or(ChainError::MissingBlock { height: start })?,
+            );
+        }
+
+        Ok(self.config.pow.next_bits(
+            &previous.header,
+            window_start.as_ref().map(|block| &block.header),
+        ))
+    }
+    // Create a new block with `transactions` on top of the latest block,
+    // with the current target of the chain, ready to be mined. It starts
+    // with a coinbase that pays the reward of the block to `miner_address`.
+    pub async fn block_template(
+        &self,
+        miner_address: &str,
+        mut transactions: Vec<Transaction>,
+    ) -> Result<Block> {
+        debug!(
+            "building a block template at height {} with {} transactions.",
+            self.len(),
+            transactions.len()
+        );
+
+        let height = self.len();
+        let reward = transactions
+            .iter()
+            .fold(self.config.reward.subsidy(height), |sum, tx| {
+                sum.saturating_add(tx.fee)
+            });
+        transactions.insert(
+            0,
+            Transaction::coinbase(height, miner_address.to_string(), reward),
+        );
+
+        let bits = self.next_bits(&self.tip).await?;
+        let mut block = Block::new(height, self.tip.hash.clone(), transactions, bits);
+        block.header.state_root = self.ledger().state_root(&block)?;
+
+        Ok(block)
+    }
+    // Create a new block with `transactions` on top of the latest block,
+    // and mine it on the current thread.
+    pub async fn mine_block(
+        &self,
+        miner_address: &str,
+        transactions: Vec<Transaction>,
+    ) -> Result<Block> {
+        let mut block = self.block_template(miner_address, transactions).await?;
+        block.mine();
+
+        Ok(block)
+    }
+    // a block will only be pushed to the blockchain,
+    // once it has been validated and mined.
+    pub async fn add_block(&mut self, block: Block) -> Result<()> {
+        let bits = self.next_bits(&self.tip).await?;
+        let subsidy = self.config.reward.subsidy(block.header.id);
+        block.validate_without_state(&self.tip, bits, subsidy)?;
+
+        let diff = self.ledger().diff(&block)?;
+        self.append(block, diff).await
+    }
+    // Process a block received from anywhere: blocks whose parent is
+    // unknown wait in the orphan pool, blocks on a side branch are kept
+    // in the tree, and when a side branch becomes better than the
+    // canonical chain, according to `is_better_chain`, the chain is
+    // reorganized to it. Orphans are connected as soon as their parent is.
+    pub async fn process_block(&mut self, block: Block) -> Result<BlockStatus> {
+        if self.contains(&block.hash) || self.tree.is_orphan(&block.hash) {
+            return Ok(BlockStatus::Duplicate);
+        }
+        // cheap check, so that garbage doesn't fill the orphan pool, and a
+        // tampered body can't take the place of the real block. The target
+        // of an orphan can't be checked yet, but it can't be easier than the
+        // limit, otherwise it would cost nothing to mine.
+        if pow::from_compact(block.header.bits) > self.config.pow.limit() {
+            return Err(ChainError::InvalidTarget {
+                height: block.header.id,
+                expected: self.config.pow.limit_bits,
+                found: block.header.bits,
+            });
+        }
+        block.check()?;
+
+        if !self.contains(&block.header.previous_hash) {
+            debug!("block {} is an orphan.", block.hash);
+            self.tree.add_orphan(block);
+            return Ok(BlockStatus::Orphan);
+        }
+
+        let mut parents = vec![block.hash.clone()];
+        let status = self.connect_block(block).await?;
+
+        while let Some(parent) = parents.pop() {
+            for orphan in self.tree.take_orphans(&parent) {
+                let hash = orphan.hash.clone();
+                match self.connect_block(orphan).await {
+                    Ok(_) => parents.push(hash),
+                    Err(e) => warn!("dropping invalid orphan {hash}: {e}"),
+                }
+            }
+        }
+
+        Ok(status)
+    }
+    // Process every block of `chain` that we don't know yet,
+    // it must be a chain of our network. Returns the number of new blocks.
+    pub async fn accept_chain(&mut self, chain: &[Block]) -> Result<usize> {
+        self.check_genesis(chain)?;
+
+        let mut new_blocks = 0;
+        for block in chain {
+            if self.process_block(block.clone()).await? != BlockStatus::Duplicate {
+                new_blocks += 1;
+            }
+        }
+
+        Ok(new_blocks)
+    }
+    // Validate a block whose parent is known and add it to the tree,
+    // reorganizing the chain to its branch if it becomes the best one.
+    async fn connect_block(&mut self, block: Block) -> Result<BlockStatus> {
+        let parent = self.get_any_block(&block.header.previous_hash).await?;
+        let height = parent.header.id + 1;
+        if parent.hash != self.tip.hash {
+            self.check_fork_depth(height)?;
+        }
+        let mut window_start = None;
+
+        if self.config.pow.is_retarget_height(height) {
+            let start = height - self.config.pow.retarget_interval;
+            window_start = Some(self.ancestor(&parent, start).await?);
+        }
+        let bits = self.config.pow.next_bits(
+            &parent.header,
+            window_start.as_ref().map(|block| &block.header),
+        );
+        // the transactions of a side block are only checked if its branch
+        // becomes the canonical chain, against the state of that branch.
+        block.validate_without_state(&parent, bits, self.config.reward.subsidy(height))?;
+
+        if parent.hash == self.tip.hash {
+            let diff = self.ledger().diff(&block)?;
+            self.append(block, diff).await?;
+            return Ok(BlockStatus::Connected);
+        }
+
+        let work = self.work_of(&parent.hash)? + pow::work(block.header.bits);
+        let hash = block.hash.clone();
+        self.tree.insert(block, work);
+
+        if is_better_chain(&work, &hash, &self.work(), &self.tip.hash) {
+            self.reorganize(&hash).await?;
+            return Ok(BlockStatus::Connected);
+        }
+
+        debug!("block {hash} is on a side branch.");
+        Ok(BlockStatus::SideBranch)
+    }
+    // Make the side branch that ends in `hash` the canonical chain, if
+    // the transactions of all its blocks are valid. Otherwise the chain is left
+    // as is, and the invalid blocks are forgotten.
+    async fn reorganize(&mut self, hash: &str) -> Result<()> {
+        // walk back the branch down to the fork point.
+        let mut branch = Vec::new();
+        let mut fork = hash.to_owned();
+        while let Some(node) = self.tree.get(&fork) {
+            fork = node.block.header.previous_hash.clone();
+            branch.push(node.block.clone());
+        }
+        branch.reverse();
+
+        let fork_height = self
+            .store
+            .height_of(&fork)
+            .ok_or(ChainError::MissingBlock {
+                height: branch[0].header.id - 1,
+            })?;
+
+        // the old branch goes to the tree, it may become the best one again.
+        let mut disconnected = Vec::new();
+        for height in (fork_height + 1..self.len()).rev() {
+            let block = self
+                .store
+                .get_block_by_height(height)
+                .await?
+                .ok_or(ChainError::MissingBlock { height })?;
+            let work = self
+                .store
+                .work_at(height)
+                .ok_or(ChainError::MissingBlock { height })?;
+            let diff = self
+                .state
+                .diff_at(height)
+                .await?
+                .ok_or(ChainError::MissingBlock { height })?;
+
+            disconnected.push((block, work, diff));
+        }
+
+        // check the transactions of the new branch on a copy of the
+        // ledger, before touching anything on disk.
+        let mut ledger = self.ledger().clone();
+        for (_, _, diff) in &disconnected {
+            ledger.revert(diff);
+        }
+        let mut diffs = Vec::with_capacity(branch.len());
+        for (i, block) in branch.iter().enumerate() {
+            match ledger.diff(block) {
+                Ok(diff) => {
+                    ledger.apply(&diff);
+                    diffs.push(diff);
+                }
+                Err(e) => {
+                    warn!("not reorganizing to an invalid branch: {e}");
+                    for block in &branch[i..] {
+                        self.tree.remove(&block.hash);
+                    }
+                    return Err(e);
+                }
+            }
+        }
 
-    let is_local_valid = Block::validate_all(&local).is_ok();
-    let is_remote_valid = Block::validate_all(&remote).is_ok();
+        warn!(
+            "reorganizing the chain at height {fork_height}, {} blocks disconnected and {} connected.",
+            disconnected.len(),
+            branch.len()
+        );
+
+        self.store.truncate(fork_height + 1).await?;
+        self.state.truncate(fork_height + 1).await?;
+        for (block, diff) in branch.iter().zip(diffs) {
+            self.tree.remove(&block.hash);
+            self.store.append(block).await?;
+            self.state.push(diff).await?;
+        }
+        self.tip = branch[branch.len() - 1].clone();
 
-    if is_local_valid && is_remote_valid {
-        if local.len() > remote.len() {
-            return Ok(local);
-        } else {
-            return Ok(remote);
+        for (block, work, _) in disconnected {
+            self.tree.insert(block.clone(), work);
+            self.emit(ChainEvent::BlockDisconnected(block));
         }
-    } else if is_local_valid {
-        return Ok(local);
-    } else {
-        return Ok(remote);
+        for block in branch {
+            self.emit(ChainEvent::BlockConnected(block));
+        }
+        self.prune();
+
+        Ok(())
+    }
+    // Append a block whose transactions were checked to the canonical chain.
+    async fn append(&mut self, block: Block, diff: StateDiff) -> Result<()> {
+        self.store.append(&block).await?;
+        self.state.push(diff).await?;
+        self.tip = block.clone();
+        self.emit(ChainEvent::BlockConnected(block));
+        self.prune();
+
+        Ok(())
+    }
+    // Forget the side blocks that fork too deep below the new tip.
+    fn prune(&mut self) {
+        self.tree
+            .prune(self.tip.header.id.saturating_sub(MAX_FORK_DEPTH));
+    }
+    // Refuse a block at `height` on a side branch, if the branch forks
+    // so deep below the tip that it would be pruned right away.
+    fn check_fork_depth(&self, height: u64) -> Result<()> {
+        if height < self.tip.header.id.saturating_sub(MAX_FORK_DEPTH) {
+            return Err(ChainError::ForkTooDeep { height });
+        }
+        Ok(())
+    }
+    // Bring the ledger journal back in sync with the block log, after a
+    // crash between writing a block and its diff, or in the middle of a reorg.
+    async fn sync_state(&mut self) -> Result<()> {
+        // drop the diffs of the blocks that are not in the log anymore.
+        let mut len = self.state.len().min(self.len());
+        while len > 0 {
+            let diff = self.state.diff_at(len - 1).await?;
+            if diff.as_ref().map(StateDiff::hash) == self.store.hash_at(len - 1) {
+                break;
+            }
+            len -= 1;
+        }
+        if len < self.state.len() {
+            warn!("the ledger is ahead of the block log, reverting it to height {len}.");
+            self.state.truncate(len).await?;
+        }
+
+        for height in len..self.len() {
+            let block = self
+                .store
+                .get_block_by_height(height)
+                .await?
+                .ok_or(ChainError::MissingBlock { height })?;
+            let diff = self.ledger().diff(&block)?;
+            self.state.push(diff).await?;
+        }
+
+        Ok(())
+    }
+    // A block of the canonical chain or of a side branch.
+    async fn get_any_block(&self, hash: &str) -> Result<Block> {
+        if let Some(node) = self.tree.get(hash) {
+            return Ok(node.block.clone());
+        }
+        self.store
+            .get_block_by_hash(hash)
+            .await?
+            .ok_or(ChainError::UnknownBlock {
+                hash: hash.to_owned(),
+            })
+    }
+    // Accumulated work of the branch that ends in `hash`.
+    fn work_of(&self, hash: &str) -> Result<U256> {
+        if let Some(node) = self.tree.get(hash) {
+            return Ok(node.work);
+        }
+        self.store
+            .height_of(hash)
+            .and_then(|height| self.store.work_at(height))
+            .ok_or(ChainError::UnknownBlock {
+                hash: hash.to_owned(),
+            })
+    }
+    // The block at `height` on the branch of `block`.
+    async fn ancestor(&self, block: &Block, height: u64) -> Result<Block> {
+        let mut current = block.clone();
+
+        // walk back the side branch until it joins the canonical chain.
+        while current.header.id > height {
+            match self.tree.get(&current.hash) {
+                Some(_) => current = self.get_any_block(&current.header.previous_hash).await?,
+                None => break,
+            }
+        }
+        if current.header.id == height {
+            return Ok(current);
+        }
+        self.store
+            .get_block_by_height(height)
+            .await?
+            .ok_or(ChainError::MissingBlock { height })
+    }
+    fn emit(&self, event: ChainEvent) {
+        // it is fine to not have any subscriber.
+        let _ = self.events.send(event);
+    }
+    // Validate entire blockchain
+    pub async fn validate(&self) -> Result<()> {
+        let mut blocks = Box::pin(self.iter());
+        let mut previous: Option<Block> = None;
+        let mut ledger = Ledger::new(self.config.ledger);
+
+        while let Some(block) = blocks.next().await {
+            let block = block?;
+
+            // genesis block cant be validated
+            if let Some(previous) = &previous {
+                let bits = self.next_bits(previous).await?;
+                let subsidy = self.config.reward.subsidy(block.header.id);
+                block.validate_without_state(previous, bits, subsidy)?;
+            }
+            ledger.apply(&ledger.diff(&block)?);
+            previous = Some(block);
+        }
+        debug!("chain with {} blocks is valid", self.len());
+        Ok(())
     }
 }
-pub async fn get_latest_block() -> Result<Block, io::Error> {
-    let chain = read_all().await?;
 
-    let latest = chain.last().unwrap().to_owned();
+// Accumulated proof of work of `chain`.
+pub fn chain_work(chain: &[Block]) -> U256 {
+    chain.iter().fold(U256::zero(), |work, block| {
+        work + pow::work(block.header.bits)
+    })
+}
 
-    Ok(latest)
+// Fork choice rule: the chain with the most accumulated work wins,
+// and on equal work the one whose tip has the lowest hash,
+// so that every node picks the same chain regardless of arrival order.
+pub fn is_better_chain(work: &U256, tip: &str, other_work: &U256, other_tip: &str) -> bool {
+    match work.cmp(other_work) {
+        Ordering::Greater => true,
+        Ordering::Less => false,
+        Ordering::Equal => tip < other_tip,
+    }
 }
-// Validate entire blockchain
-pub async fn validate() -> Result<(), String> {
-    // read blockchain from the file.
-    let blockchain = read_all().await.unwrap();
 
-    if blockchain.len() < 1 {
-        return Err("Blockchain has zero blocks and need at least 1 block.".to_string());
-    };
+// Choose between two whole chains with `is_better_chain`.
+// Invalid chains are never chosen, and chains from
+// different networks can't be compared.
+pub fn choose_chain<'a>(
+    local: &'a [Block],
+    remote: &'a [Block],
+    config: &ChainConfig,
+) -> Result<&'a [Block]> {
+    if let (Some(local), Some(remote)) = (local.first(), remote.first()) {
+        if local.hash != remote.hash {
+            return Err(ChainError::GenesisMismatch {
+                expected: local.hash.clone(),
+                found: remote.hash.clone(),
+            });
+        }
+    }
 
-    for i in 0..blockchain.len() {
-        let block = blockchain.get(i);
+    let local_valid = Block::validate_all(local, config).and(tip(local));
+    let remote_valid = Block::validate_all(remote, config).and(tip(remote));
 
-        // genesis block cant be validated
-        if i == 0 {
-            continue;
-        };
+    match (local_valid, remote_valid) {
+        (Ok(local_tip), Ok(remote_tip)) => {
+            let local_work = chain_work(local);
+            let remote_work = chain_work(remote);
 
-        if let Some(curr_block) = block {
-            let result = curr_block.validate().await;
-            if result.is_err() {
-                return Err(format!("Block with id {i} is invalid."));
+            if is_better_chain(&remote_work, &remote_tip.hash, &local_work, &local_tip.hash) {
+                Ok(remote)
+            } else {
+                Ok(local)
             }
         }
+        (Ok(_), Err(e)) => {
+            warn!("remote chain is invalid: {e}");
+            Ok(local)
+        }
+        (Err(e), Ok(_)) => {
+            warn!("local chain is invalid: {e}");
+            Ok(remote)
+        }
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+fn tip(chain: &[Block]) -> Result<&Block> {
+    chain.last().ok_or(ChainError::EmptyChain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static DIRS: AtomicU32 = AtomicU32::new(0);
+
+    // a fresh directory for a single test.
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "blockchain-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // a network where blocks are cheap to mine, and that
+    // retargets every `retarget_interval` blocks.
+    fn config(retarget_interval: u64) -> ChainConfig {
+        GenesisSpec {
+            pow: PowParams {
+                bits: 0x2000ffff,
+                limit_bits: 0x2000ffff,
+                target_block_time: 10_000,
+                retarget_interval,
+                max_adjustment: 4,
+            },
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn address(byte: &str) -> String {
+        byte.repeat(32)
+    }
+
+    // a new chain with `len` blocks after the genesis block, mined by `miner`.
+    async fn mined_chain(config: ChainConfig, len: u64, miner: &str) -> Blockchain {
+        let mut chain = Blockchain::new(temp_dir(), config).await.unwrap();
+        mine(&mut chain, len, miner).await;
+        chain
+    }
+
+    async fn mine(chain: &mut Blockchain, len: u64, miner: &str) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        for _ in 0..len {
+            let block = chain.mine_block(miner, Vec::new()).await.unwrap();
+            chain.add_block(block.clone()).await.unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[tokio::test]
+    async fn whole_chain_must_start_at_genesis() {
+        let config = config(10);
+        let chain = mined_chain(config.clone(), 12, &address("aa")).await;
+        let blocks = chain.read_all().await.unwrap();
+
+        assert!(Block::validate_all(&blocks, &config).is_ok());
+        // the retarget at height 10 would have a window of a single block.
+        assert!(matches!(
+            Block::validate_all(&blocks[9..], &config),
+            Err(ChainError::GenesisMismatch { .. })
+        ));
+        assert!(choose_chain(&[], &blocks[9..], &config).is_err());
+        assert_eq!(choose_chain(&[], &blocks, &config).unwrap().len(), 13);
+
+        let other = Blockchain::new(temp_dir(), ChainConfig::default())
+            .await
+            .unwrap();
+        let genesis = other.read_all().await.unwrap();
+        assert!(Block::validate_all(&genesis, &config).is_err());
+    }
+
+    #[tokio::test]
+    async fn orphans_connect_once_their_parent_does() {
+        let mut chain = mined_chain(config(100_000), 0, &address("aa")).await;
+        let mut other = mined_chain(config(100_000), 0, &address("bb")).await;
+        let blocks = mine(&mut other, 3, &address("bb")).await;
+
+        for block in blocks[1..].iter().rev() {
+            let status = chain.process_block(block.clone()).await.unwrap();
+            assert_eq!(status, BlockStatus::Orphan);
+        }
+        assert_eq!(chain.tree.orphans_len(), 2);
+        assert_eq!(
+            chain.process_block(blocks[2].clone()).await.unwrap(),
+            BlockStatus::Duplicate
+        );
+
+        let status = chain.process_block(blocks[0].clone()).await.unwrap();
+        assert_eq!(status, BlockStatus::Connected);
+        assert_eq!(chain.len(), 4);
+        assert_eq!(chain.latest().hash, blocks[2].hash);
+        assert_eq!(chain.tree.orphans_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn side_branch_with_more_work_becomes_the_chain() {
+        let (aa, bb) = (address("aa"), address("bb"));
+        let mut chain = Blockchain::new(temp_dir(), config(100_000)).await.unwrap();
+        let old = mine(&mut chain, 2, &aa).await;
+        let mut other = mined_chain(config(100_000), 0, &bb).await;
+        let new = mine(&mut other, 3, &bb).await;
+        let subsidy = chain.config().reward.subsidy(1);
+        assert_eq!(chain.ledger().balance(&aa), 2 * subsidy);
+
+        let mut events = chain.subscribe();
+        let status = chain.process_block(new[0].clone()).await.unwrap();
+        assert_eq!(status, BlockStatus::SideBranch);
+        assert_eq!(chain.latest().hash, old[1].hash);
+        assert!(chain.contains(&new[0].hash));
+
+        // with as much work as the tip, the second block only wins
+        // on the hash, but the third one always does.
+        chain.process_block(new[1].clone()).await.unwrap();
+        let status = chain.process_block(new[2].clone()).await.unwrap();
+        assert_eq!(status, BlockStatus::Connected);
+        assert_eq!(chain.len(), 4);
+        assert_eq!(chain.latest().hash, new[2].hash);
+        assert_eq!(chain.work(), chain_work(&other.read_all().await.unwrap()));
+
+        // the old tip is disconnected first, then the new branch
+        // is connected from the fork point up.
+        let mut order = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            order.push(match event {
+                ChainEvent::BlockConnected(block) => ("connected", block.hash),
+                ChainEvent::BlockDisconnected(block) => ("disconnected", block.hash),
+            });
+        }
+        let expected = [
+            ("disconnected", old[1].hash.clone()),
+            ("disconnected", old[0].hash.clone()),
+            ("connected", new[0].hash.clone()),
+            ("connected", new[1].hash.clone()),
+            ("connected", new[2].hash.clone()),
+        ];
+        assert_eq!(order, expected);
+
+        // the ledger follows the new branch, and the old one is kept aside.
+        assert_eq!(chain.ledger().balance(&aa), 0);
+        assert_eq!(chain.ledger().balance(&bb), 3 * subsidy);
+        assert!(chain.contains(&old[1].hash));
+        assert_eq!(chain.store.height_of(&old[1].hash), None);
+
+        // and all of it survives a restart.
+        let dir = chain.dir().to_path_buf();
+        drop(chain);
+        let chain = Blockchain::new(&dir, config(100_000)).await.unwrap();
+        assert_eq!(chain.latest().hash, new[2].hash);
+        assert_eq!(chain.ledger().balance(&aa), 0);
+        assert_eq!(chain.ledger().balance(&bb), 3 * subsidy);
+        chain.validate().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fork_too_deep_is_refused() {
+        let (aa, bb) = (address("aa"), address("bb"));
+        let mut chain = mined_chain(config(100_000), 0, &aa).await;
+        let blocks = mine(&mut chain, MAX_FORK_DEPTH + 3, &aa).await;
+
+        // forks right below the tip minus `MAX_FORK_DEPTH` are refused.
+        let other = mined_chain(config(100_000), 1, &bb).await;
+        let deep = other.latest().clone();
+        assert!(matches!(
+            chain.process_block(deep).await,
+            Err(ChainError::ForkTooDeep { height: 1 })
+        ));
+        assert!(!chain.contains(&other.latest().hash));
+
+        // but a fork at that height is kept.
+        let mut other = mined_chain(config(100_000), 0, &bb).await;
+        for block in &blocks[..2] {
+            other.add_block(block.clone()).await.unwrap();
+        }
+        let fork = mine(&mut other, 1, &bb).await.remove(0);
+        let status = chain.process_block(fork).await.unwrap();
+        assert_eq!(status, BlockStatus::SideBranch);
+    }
+
+    #[tokio::test]
+    async fn headers_after_the_fork_point() {
+        let chain = mined_chain(config(100_000), 3, &address("aa")).await;
+        let blocks = chain.read_all().await.unwrap();
+        let genesis = chain.genesis_hash().to_owned();
+        let only_genesis = [genesis.clone()];
+
+        let headers = chain.headers_after(&only_genesis, 10).await.unwrap();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[0], blocks[1].header);
+
+        // the first known hash of the locator is the fork point.
+        let locator = [
+            "unknown".to_owned(),
+            blocks[2].hash.clone(),
+            genesis.clone(),
+        ];
+        let headers = chain.headers_after(&locator, 10).await.unwrap();
+        assert_eq!(headers, [blocks[3].header.clone()]);
+        assert_eq!(
+            chain.headers_after(&only_genesis, 1).await.unwrap().len(),
+            1
+        );
+        assert!(chain
+            .headers_after(&chain.locator(), 10)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // none of the locator is known, the peer is on another network.
+        assert!(matches!(
+            chain.headers_after(&["unknown".to_owned()], 10).await,
+            Err(ChainError::GenesisMismatch { .. })
+        ));
+        assert!(chain.headers_after(&[], 10).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn headers_from_the_future_are_refused() {
+        let chain = mined_chain(config(100_000), 1, &address("aa")).await;
+
+        let mut block = chain
+            .block_template(&address("bb"), Vec::new())
+            .await
+            .unwrap();
+        block.mine();
+        assert!(chain
+            .validate_headers(&[], &[block.header.clone()])
+            .await
+            .is_ok());
+
+        // a bit more than the drift that is tolerated.
+        block.header.timestamp += 2 * 60 * 60 * 1000 + 60 * 1000;
+        block.hash = block.calculate_hash();
+        block.mine();
+        assert!(matches!(
+            chain.validate_headers(&[], &[block.header]).await,
+            Err(ChainError::InvalidTimestamp { height: 2 })
+        ));
     }
-    Ok(())
 }
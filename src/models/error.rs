@@ -0,0 +1,96 @@
+use thiserror::Error;
+use tokio::io;
+
+#[derive(Debug, Error)]
+pub enum ChainError {
+    #[error("block {height} passed an invalid previous hash")]
+    InvalidPreviousHash { height: u64 },
+    #[error("invalid block id {height}, expected {expected}")]
+    InvalidHeight { height: u64, expected: u64 },
+    #[error("block {height} has a hash that doesn't match its contents")]
+    InvalidHash { height: u64 },
+    #[error("block {height} has transactions that don't match its merkle root")]
+    InvalidMerkleRoot { height: u64 },
+    #[error("block {height} does not satisfy the proof of work")]
+    InvalidPow { height: u64 },
+    #[error("block {height} has target {found:#x}, expected {expected:#x}")]
+    InvalidTarget {
+        height: u64,
+        expected: u32,
+        found: u32,
+    },
+    #[error("block {height} has an invalid timestamp")]
+    InvalidTimestamp { height: u64 },
+    #[error("block {height} has transaction {tx} with an invalid signature")]
+    InvalidSignature { height: u64, tx: String },
+    #[error("block {height} has transaction {tx} that spends a missing or already spent output")]
+    MissingOutput { height: u64, tx: String },
+    #[error("block {height} has transaction {tx} that spends outputs of someone else, or none")]
+    InvalidSpend { height: u64, tx: String },
+    #[error("block {height} has transaction {tx} whose inputs don't match its outputs and fee")]
+    UnbalancedTransaction { height: u64, tx: String },
+    #[error("block {height} has transaction {tx} with nonce {found}, expected {expected}")]
+    InvalidNonce {
+        height: u64,
+        tx: String,
+        expected: u64,
+        found: u64,
+    },
+    #[error("block {height} doesn't start with a valid coinbase")]
+    InvalidCoinbase { height: u64 },
+    #[error("block {height} pays a reward of {found} to its miner, expected {expected}")]
+    InvalidReward {
+        height: u64,
+        expected: u64,
+        found: u64,
+    },
+    #[error("block {height} has a state root that doesn't match its transactions")]
+    InvalidStateRoot { height: u64 },
+    #[error("transaction {tx} is already in the mempool")]
+    DuplicateTransaction { tx: String },
+    #[error("transaction {tx} has a sender with too many transactions in the mempool")]
+    TooManyTransactions { tx: String },
+    #[error("transaction {tx} has a fee rate too low for the mempool")]
+    MempoolFull { tx: String },
+    #[error("insufficient funds, {needed} needed but {available} available")]
+    InsufficientFunds { needed: u64, available: u64 },
+    #[error("genesis mismatch, expected {expected} but got {found}")]
+    GenesisMismatch { expected: String, found: String },
+    #[error("chain has zero blocks")]
+    EmptyChain,
+    #[error("unknown block {hash}")]
+    UnknownBlock { hash: String },
+    #[error("block {height} is on a side branch that forks too deep below the tip")]
+    ForkTooDeep { height: u64 },
+    #[error("block {height} is missing from the block log")]
+    MissingBlock { height: u64 },
+    #[error("block {height} has no transaction at index {index}")]
+    MissingTransaction { height: u64, index: usize },
+    #[error("invalid genesis spec: {0}")]
+    InvalidSpec(#[from] toml::de::Error),
+    #[error("invalid consensus parameters: {0}")]
+    InvalidParams(String),
+    #[error("i/o error: {0}")]
+    Io(#[from] io::Error),
+    #[error("could not decode: {0}")]
+    Decode(#[from] speedy::Error),
+}
+
+impl ChainError {
+    // If the error is not the fault of the peer that sent the data: a
+    // failure of this node, like a failed read, or data that may be valid
+    // but doesn't fit our chain anymore, e.g. headers that fork from a
+    // block that a reorg took out of it.
+    pub fn is_local(&self) -> bool {
+        matches!(
+            self,
+            Self::Io(_)
+                | Self::Decode(_)
+                | Self::MissingBlock { .. }
+                | Self::UnknownBlock { .. }
+                | Self::ForkTooDeep { .. }
+        )
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ChainError>;
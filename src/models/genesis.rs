@@ -0,0 +1,117 @@
+use super::{
+    block::{self, Block, BlockHeader},
+    error::{ChainError, Result},
+    pow::PowParams,
+    reward::RewardParams,
+    state::{Ledger, LedgerKind},
+    transaction::{self, Transaction},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, path::Path};
+use tokio::fs;
+
+// Describes the genesis block of a network. Every node that loads the
+// same spec derives exactly the same genesis block, and so the same hash.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GenesisSpec {
+    pub chain_name: String,
+    // unix timestamp in milliseconds.
+    pub timestamp: u64,
+    pub data: String,
+    #[serde(default)]
+    pub pow: PowParams,
+    #[serde(default)]
+    pub reward: RewardParams,
+    // how the state of the chain is modelled, "utxo" or "account".
+    #[serde(default)]
+    pub ledger: LedgerKind,
+    // address -> amount pre-allocated at genesis.
+    #[serde(default)]
+    pub balances: BTreeMap<String, u64>,
+}
+
+impl Default for GenesisSpec {
+    fn default() -> Self {
+        Self {
+            chain_name: "devnet".to_string(),
+            timestamp: 1668816000000,
+            data: "Genesis".to_string(),
+            pow: PowParams::default(),
+            reward: RewardParams::default(),
+            ledger: LedgerKind::default(),
+            balances: BTreeMap::new(),
+        }
+    }
+}
+
+impl GenesisSpec {
+    // Load a spec from a toml file.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let buf = fs::read_to_string(path).await?;
+        let spec: Self = toml::from_str(&buf)?;
+
+        spec.pow.check()?;
+        spec.reward.check()?;
+
+        if let Some(address) = spec
+            .balances
+            .keys()
+            .find(|a| !transaction::is_valid_address(a))
+        {
+            return Err(ChainError::InvalidParams(format!(
+                "invalid address in balances: {address}"
+            )));
+        }
+
+        Ok(spec)
+    }
+    // The genesis block is not mined, its hash only depends on the spec.
+    // It has no parent, so its previous hash commits to the chain name, data,
+    // proof of work and reward parameters instead, and the balances are minted
+    // by its transactions, so that networks that differ in any of them have
+    // different genesis hashes.
+    pub fn block(&self) -> Block {
+        let (pow, reward) = (&self.pow, &self.reward);
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}\n{}", self.chain_name, self.data));
+        hasher.update(format!(
+            "\npow {:08x} {:08x} {} {} {}",
+            pow.bits,
+            pow.limit_bits,
+            pow.target_block_time,
+            pow.retarget_interval,
+            pow.max_adjustment
+        ));
+        hasher.update(format!(
+            "\nreward {} {}",
+            reward.subsidy, reward.halving_interval
+        ));
+
+        // a BTreeMap iterates in a deterministic order.
+        let transactions: Vec<_> = self
+            .balances
+            .iter()
+            .map(|(address, amount)| Transaction::mint(address.clone(), *amount))
+            .collect();
+
+        let mut genesis = Block {
+            header: BlockHeader {
+                id: 0,
+                previous_hash: format!("{:x}", hasher.finalize()),
+                timestamp: self.timestamp,
+                nonce: u64::default(),
+                bits: self.pow.bits,
+                merkle_root: block::merkle_root(&transactions),
+                state_root: String::new(),
+            },
+            hash: String::default(),
+            transactions,
+        };
+        genesis.header.state_root = Ledger::new(self.ledger)
+            .state_root(&genesis)
+            .expect("genesis transactions only mint coins");
+        genesis.hash = genesis.calculate_hash();
+        genesis
+    }
+}
@@ -0,0 +1,83 @@
+use super::{block::Block, transaction::Transaction};
+use libp2p::gossipsub::{IdentTopic, PeerScoreParams, TopicHash, TopicScoreParams};
+use speedy::{Readable, Writable};
+use std::time::Duration;
+
+// version of the messages of the topics, bumped on incompatible changes.
+const VERSION: u32 = 1;
+
+// The gossipsub topics of a network. Every network has its own, named
+// after the hash of its genesis block, so that nodes of other networks
+// never receive its messages.
+#[derive(Debug, Clone)]
+pub struct Topics {
+    // carries `BlockMessage`s.
+    pub blocks: IdentTopic,
+    // carries `TxMessage`s.
+    pub transactions: IdentTopic,
+}
+
+impl Topics {
+    pub fn new(chain_id: &str) -> Self {
+        Self {
+            blocks: IdentTopic::new(format!("/{chain_id}/blocks/{VERSION}")),
+            transactions: IdentTopic::new(format!("/{chain_id}/txs/{VERSION}")),
+        }
+    }
+    pub fn all(&self) -> [&IdentTopic; 2] {
+        [&self.blocks, &self.transactions]
+    }
+    // Gossipsub peer scoring: the peers that relay messages we reject,
+    // see `report_message_validation_result`, lose score until gossipsub
+    // ignores them.
+    pub fn peer_score_params(&self) -> PeerScoreParams {
+        let mut params = PeerScoreParams::default();
+
+        for topic in self.all() {
+            let topic_params = TopicScoreParams {
+                time_in_mesh_quantum: Duration::from_secs(1),
+                // blocks are rare, peers must not be penalized
+                // for delivering few messages.
+                mesh_message_deliveries_weight: 0.0,
+                mesh_failure_penalty_weight: 0.0,
+                invalid_message_deliveries_weight: -100.0,
+                ..Default::default()
+            };
+            params.topics.insert(topic.hash(), topic_params);
+        }
+        params
+    }
+    // Decode a message received on `topic`, `None` if it is
+    // malformed or comes from a topic we don't know.
+    pub fn decode(&self, topic: &TopicHash, data: &[u8]) -> Option<Message> {
+        if *topic == self.blocks.hash() {
+            BlockMessage::read_from_buffer(data)
+                .ok()
+                .map(Message::Block)
+        } else if *topic == self.transactions.hash() {
+            TxMessage::read_from_buffer(data).ok().map(Message::Tx)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Writable, Readable)]
+pub enum BlockMessage {
+    // a new block, its missing ancestors are fetched from the peer
+    // that relayed it, see `SyncRequest::GetBlock`.
+    Block(Block),
+}
+
+#[derive(Debug, Clone, Writable, Readable)]
+pub enum TxMessage {
+    // a new transaction, for the mempool.
+    Transaction(Transaction),
+}
+
+// A message received on one of the topics.
+#[derive(Debug, Clone)]
+pub enum Message {
+    Block(BlockMessage),
+    Tx(TxMessage),
+}
@@ -0,0 +1,580 @@
+use super::{
+    block::{Block, BlockHeader},
+    pow::U256,
+};
+use libp2p::{request_response::RequestId, PeerId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// how long a node waits for a peer to send its headers, before it
+// considers that it is alone and that its chain is up to date.
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+// blocks requested at once from a peer.
+const BATCH_SIZE: usize = 16;
+// blocks downloaded at most ahead of the next block to connect, so
+// that a slow peer can't make us buffer the whole chain.
+const WINDOW: usize = 1024;
+// times the headers of a peer are asked again from scratch, see
+// `InitialSync::restart_headers`, before we give up on them.
+const MAX_HEADER_RESTARTS: u32 = 3;
+
+// What became of blocks received from a peer, see `InitialSync::on_blocks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Accepted,
+    // the response to a request we don't wait for anymore, because the
+    // download was aborted since, its blocks are of no use.
+    Stale,
+    // blocks of another chain than the headers the peer sent, or none:
+    // the chain of the peer was probably reorganized since.
+    Mismatch,
+    // tampered with, or more blocks than we asked for.
+    Invalid,
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    // the headers of the chain of the peer after the fork point with
+    // ours, checked with `Blockchain::validate_headers`.
+    headers: Vec<BlockHeader>,
+    // accumulated work of the chain of the peer.
+    work: U256,
+    // if we wait for headers from the peer.
+    requesting_headers: bool,
+    // the request of the blocks we wait for from the peer,
+    // and the blocks as indexes of the target.
+    requesting_blocks: Option<(RequestId, Range<usize>)>,
+    // times the headers of the peer were asked again from scratch.
+    restarts: u32,
+}
+
+impl PeerState {
+    // If the chain of the peer has `header`.
+    fn has(&self, header: &BlockHeader) -> bool {
+        self.headers.first().is_some_and(|first| {
+            header
+                .id
+                .checked_sub(first.id)
+                .and_then(|i| self.headers.get(i as usize))
+                == Some(header)
+        })
+    }
+}
+
+// Headers-first initial block download. The headers of every peer are
+// downloaded in parallel and checked as they arrive, without the bodies.
+// Once every peer sent its headers, the chain with the most work becomes
+// the target, if it has more work than ours, and its blocks are downloaded
+// by batches from every peer that has them, in parallel. The blocks are
+// buffered and handed out in order, to be validated and connected one by
+// one. The node doesn't mine until the download is over.
+#[derive(Debug)]
+pub struct InitialSync {
+    started: Instant,
+    peers: HashMap<PeerId, PeerState>,
+    // the headers of the chain being downloaded.
+    target: Vec<BlockHeader>,
+    // index in the target of the next block to connect.
+    next: usize,
+    // batches of blocks of the target left to request, by first index.
+    pending: BTreeMap<usize, usize>,
+    // blocks received but not connected yet, by index in the target.
+    received: BTreeMap<usize, Block>,
+    synced: bool,
+}
+
+impl Default for InitialSync {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            peers: HashMap::new(),
+            target: Vec::new(),
+            next: 0,
+            pending: BTreeMap::new(),
+            received: BTreeMap::new(),
+            synced: false,
+        }
+    }
+}
+
+impl InitialSync {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // If the node caught up with its peers, or has none.
+    pub fn is_synced(&self) -> bool {
+        self.synced || (self.peers.is_empty() && self.started.elapsed() > GRACE_PERIOD)
+    }
+    // A new peer, whose headers we request right away.
+    pub fn add_peer(&mut self, peer: PeerId) {
+        self.peers.insert(
+            peer,
+            PeerState {
+                requesting_headers: true,
+                ..Default::default()
+            },
+        );
+    }
+    // `peer` disconnected or misbehaved, the blocks it had to send
+    // are requested from other peers.
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        if let Some((_, Range { start, end })) = self
+            .peers
+            .remove(peer)
+            .and_then(|state| state.requesting_blocks)
+        {
+            self.pending.insert(start, end);
+        }
+    }
+    // The headers we have from `peer`, if we are waiting for more.
+    pub fn headers_of(&self, peer: &PeerId) -> Option<&[BlockHeader]> {
+        match self.peers.get(peer) {
+            Some(state) if state.requesting_headers => Some(&state.headers),
+            _ => None,
+        }
+    }
+    // Checked `headers` from `peer`, with the work of its chain so far.
+    // `full` if the response was full, so the peer may have more.
+    pub fn on_headers(&mut self, peer: &PeerId, headers: Vec<BlockHeader>, work: U256, full: bool) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            if !headers.is_empty() {
+                state.work = work;
+            }
+            state.headers.extend(headers);
+            state.requesting_headers = full;
+        }
+    }
+    // Forget the headers of `peer` to ask them again from scratch, when its
+    // chain or ours was reorganized since it sent them. The blocks it had to
+    // send are requested from other peers. False if they were asked again
+    // too many times already, we then go on without them.
+    pub fn restart_headers(&mut self, peer: &PeerId) -> bool {
+        let state = match self.peers.get_mut(peer) {
+            Some(state) => state,
+            None => return false,
+        };
+        if let Some((_, Range { start, end })) = state.requesting_blocks.take() {
+            self.pending.insert(start, end);
+        }
+        state.headers.clear();
+        state.work = U256::zero();
+        state.requesting_headers = state.restarts < MAX_HEADER_RESTARTS;
+        state.restarts += 1;
+
+        state.requesting_headers
+    }
+    // Our request for the headers of `peer` failed, we
+    // go on with the headers it sent so far.
+    pub fn on_headers_failed(&mut self, peer: &PeerId) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            state.requesting_headers = false;
+        }
+    }
+    // Blocks received from `peer` in response to `request`. Some of
+    // them may be missing if they didn't fit in the response, they are
+    // asked again.
+    pub fn on_blocks(&mut self, peer: &PeerId, request: RequestId, blocks: Vec<Block>) -> Delivery {
+        let range = match self.peers.get_mut(peer) {
+            Some(state) => match state.requesting_blocks.take() {
+                Some((id, range)) if id == request => range,
+                other => {
+                    state.requesting_blocks = other;
+                    return Delivery::Stale;
+                }
+            },
+            None => return Delivery::Stale,
+        };
+        if blocks.len() > range.len() || blocks.iter().any(|block| block.check().is_err()) {
+            self.pending.insert(range.start, range.end);
+            return Delivery::Invalid;
+        }
+        let matching = !blocks.is_empty()
+            && blocks
+                .iter()
+                .zip(&self.target[range.clone()])
+                .all(|(block, header)| block.header == *header);
+
+        if !matching {
+            self.pending.insert(range.start, range.end);
+            return Delivery::Mismatch;
+        }
+        if blocks.len() < range.len() {
+            self.pending.insert(range.start + blocks.len(), range.end);
+        }
+        for (i, block) in blocks.into_iter().enumerate() {
+            self.received.insert(range.start + i, block);
+        }
+        Delivery::Accepted
+    }
+    // Our `request` for blocks to `peer` failed, they are asked
+    // to other peers, or to the same one again.
+    pub fn on_blocks_failed(&mut self, peer: &PeerId, request: RequestId) {
+        if let Some(state) = self.peers.get_mut(peer) {
+            match state.requesting_blocks.take() {
+                Some((id, Range { start, end })) if id == request => {
+                    self.pending.insert(start, end);
+                }
+                other => state.requesting_blocks = other,
+            }
+        }
+    }
+    // The next block of the target to connect, if it was received.
+    pub fn next_block(&mut self) -> Option<Block> {
+        let block = self.received.remove(&self.next)?;
+        self.next += 1;
+
+        if self.next == self.target.len() {
+            self.target.clear();
+            self.next = 0;
+        }
+        Some(block)
+    }
+    // The block of `header` is invalid, so is the rest of the chain of
+    // the peers that sent it. They are forgotten, and the next best
+    // chain is picked. The responses to the requests in flight to the
+    // other peers are stale from now on, see `Delivery::Stale`.
+    pub fn abort(&mut self, header: &BlockHeader) -> Vec<PeerId> {
+        let peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.has(header))
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        for peer in &peers {
+            self.peers.remove(peer);
+        }
+        for state in self.peers.values_mut() {
+            state.requesting_blocks = None;
+        }
+        self.target.clear();
+        self.next = 0;
+        self.pending.clear();
+        self.received.clear();
+        peers
+    }
+    // Pick the target once every peer sent its headers, and assign the
+    // next batches of blocks to the peers that have them and are idle.
+    // `work` is the work of our chain. `request` sends a request to a
+    // peer, for blocks by height and count, and returns its id.
+    pub fn schedule(&mut self, work: U256, mut request: impl FnMut(PeerId, u64, u64) -> RequestId) {
+        if self.target.is_empty() && !self.pick_target(work) {
+            return;
+        }
+
+        for (peer, state) in self.peers.iter_mut() {
+            if state.requesting_blocks.is_some() {
+                continue;
+            }
+            let batch = self
+                .pending
+                .iter()
+                .map(|(start, end)| *start..*end)
+                .take_while(|range| range.start < self.next + WINDOW)
+                .find(|range| state.has(&self.target[range.end - 1]));
+
+            if let Some(range) = batch {
+                self.pending.remove(&range.start);
+                let id = request(*peer, self.target[range.start].id, range.len() as u64);
+                state.requesting_blocks = Some((id, range));
+            }
+        }
+    }
+    fn pick_target(&mut self, work: U256) -> bool {
+        if self.peers.values().any(|state| state.requesting_headers) {
+            return false;
+        }
+        let best = self
+            .peers
+            .values()
+            .filter(|state| state.work > work)
+            .max_by_key(|state| state.work);
+
+        let best = match best {
+            Some(best) => best,
+            None => {
+                self.synced = true;
+                return false;
+            }
+        };
+        self.target = best.headers.clone();
+        self.next = 0;
+        self.received.clear();
+        self.pending = (0..self.target.len())
+            .step_by(BATCH_SIZE)
+            .map(|start| (start, self.target.len().min(start + BATCH_SIZE)))
+            .collect();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{
+        sync::{self, SyncBehaviour, SyncRequest},
+        transaction::Transaction,
+    };
+
+    // `len` mined blocks from height 1, on top of a genesis block with
+    // hash `parent`, `tag` makes different chains with the same parent.
+    fn chain(parent: &str, len: u64, tag: &str) -> Vec<Block> {
+        let mut blocks: Vec<Block> = Vec::new();
+        for id in 1..=len {
+            let previous = blocks.last().map_or(parent.to_owned(), |b| b.hash.clone());
+            let coinbase = Transaction::coinbase(id, tag.repeat(32), 50);
+            let mut block = Block::new(id, previous, vec![coinbase], 0x2000ffff);
+            block.hash = block.calculate_hash();
+            block.mine();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn headers(blocks: &[Block]) -> Vec<BlockHeader> {
+        blocks.iter().map(|block| block.header.clone()).collect()
+    }
+
+    // Sends nothing, but hands out request ids like the network does.
+    struct Network {
+        behaviour: SyncBehaviour,
+    }
+
+    // a block request: the peer, the first height and the count.
+    type Request = (PeerId, u64, u64, RequestId);
+
+    impl Network {
+        fn new() -> Self {
+            Self {
+                behaviour: sync::new_behaviour(),
+            }
+        }
+        fn schedule(&mut self, ibd: &mut InitialSync, work: u64) -> Vec<Request> {
+            let mut sent = Vec::new();
+            ibd.schedule(U256::from(work), |peer, from, count| {
+                let request = SyncRequest::GetBlocks { from, count };
+                let id = self.behaviour.send_request(&peer, request);
+                sent.push((peer, from, count, id));
+                id
+            });
+            sent
+        }
+    }
+
+    // the blocks a request asks for.
+    fn answer(blocks: &[Block], (_, from, count, _): &Request) -> Vec<Block> {
+        blocks[*from as usize - 1..(from + count) as usize - 1].to_vec()
+    }
+
+    #[test]
+    fn downloads_the_best_chain_from_every_peer_that_has_it() {
+        let blocks = chain("genesis", 40, "aa");
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut network = Network::new();
+        let mut ibd = InitialSync::new();
+        ibd.add_peer(a);
+        ibd.add_peer(b);
+
+        ibd.on_headers(&a, headers(&blocks), U256::from(40), false);
+        // b didn't send its headers yet.
+        assert!(network.schedule(&mut ibd, 0).is_empty());
+        assert!(!ibd.is_synced());
+
+        ibd.on_headers(&b, headers(&blocks[..20]), U256::from(20), false);
+        let sent = network.schedule(&mut ibd, 0);
+        assert!(!sent.is_empty());
+        // b only gets blocks it has.
+        for (_, from, count, _) in sent.iter().filter(|(peer, ..)| *peer == b) {
+            assert!(from + count - 1 <= 20);
+        }
+
+        // the blocks come out in order, whatever the order of the responses.
+        let mut sent: Vec<_> = sent.into_iter().rev().collect();
+        let mut connected = Vec::new();
+        while let Some(request) = sent.pop() {
+            let delivery = ibd.on_blocks(&request.0, request.3, answer(&blocks, &request));
+            assert_eq!(delivery, Delivery::Accepted);
+            while let Some(block) = ibd.next_block() {
+                connected.push(block.hash);
+            }
+            sent.extend(network.schedule(&mut ibd, connected.len() as u64));
+        }
+        let hashes: Vec<_> = blocks.iter().map(|block| block.hash.clone()).collect();
+        assert_eq!(connected, hashes);
+
+        // nobody has more work than us anymore.
+        assert!(network.schedule(&mut ibd, 40).is_empty());
+        assert!(ibd.is_synced());
+    }
+
+    #[test]
+    fn short_responses_and_failures_are_asked_again() {
+        let blocks = chain("genesis", 16, "aa");
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut network = Network::new();
+        let mut ibd = InitialSync::new();
+        ibd.add_peer(a);
+        ibd.on_headers(&a, headers(&blocks), U256::from(16), false);
+
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent.len(), 1);
+        let (peer, from, count, id) = sent[0];
+        assert_eq!((peer, from, count), (a, 1, 16));
+
+        // only the first 10 blocks fit in the response.
+        let delivery = ibd.on_blocks(&a, id, blocks[..10].to_vec());
+        assert_eq!(delivery, Delivery::Accepted);
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent[0].1..sent[0].1 + sent[0].2, 11..17);
+
+        // the request fails, and a disconnected peer leaves its blocks
+        // to the others.
+        ibd.on_blocks_failed(&a, sent[0].3);
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent[0].1, 11);
+        ibd.add_peer(b);
+        ibd.on_headers(&b, headers(&blocks), U256::from(16), false);
+        ibd.remove_peer(&a);
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0, sent[0].1, sent[0].2), (b, 11, 6));
+    }
+
+    #[test]
+    fn stale_and_invalid_deliveries() {
+        let blocks = chain("genesis", 32, "aa");
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut network = Network::new();
+        let mut ibd = InitialSync::new();
+        for peer in [a, b] {
+            ibd.add_peer(peer);
+            ibd.on_headers(&peer, headers(&blocks), U256::from(32), false);
+        }
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent.len(), 2);
+        let [first, second] = [sent[0], sent[1]];
+
+        // a response to a request that already failed is stale.
+        ibd.on_blocks_failed(&first.0, first.3);
+        let delivery = ibd.on_blocks(&first.0, first.3, answer(&blocks, &first));
+        assert_eq!(delivery, Delivery::Stale);
+        let retry = network.schedule(&mut ibd, 0)[0];
+        assert_eq!(retry.1, first.1);
+
+        // blocks of another chain, or none, don't match the headers,
+        // and are asked again.
+        let other = chain("genesis", 32, "bb");
+        let delivery = ibd.on_blocks(&retry.0, retry.3, answer(&other, &retry));
+        assert_eq!(delivery, Delivery::Mismatch);
+        let retry = network.schedule(&mut ibd, 0)[0];
+        assert_eq!(retry.1, first.1);
+
+        assert_eq!(
+            ibd.on_blocks(&retry.0, retry.3, Vec::new()),
+            Delivery::Mismatch
+        );
+        let retry = network.schedule(&mut ibd, 0)[0];
+
+        // tampered blocks, or more than asked for, are invalid.
+        let mut tampered = answer(&blocks, &retry);
+        tampered[3].transactions.clear();
+        let delivery = ibd.on_blocks(&retry.0, retry.3, tampered);
+        assert_eq!(delivery, Delivery::Invalid);
+        let retry = network.schedule(&mut ibd, 0)[0];
+
+        let mut more = answer(&blocks, &retry);
+        more.push(blocks[31].clone());
+        assert_eq!(ibd.on_blocks(&retry.0, retry.3, more), Delivery::Invalid);
+        let retry = network.schedule(&mut ibd, 0)[0];
+        assert_eq!(retry.1, first.1);
+
+        // once the download is aborted, the requests in flight are stale.
+        let aborted = ibd.abort(&blocks[30].header);
+        assert_eq!(aborted.len(), 2);
+        let delivery = ibd.on_blocks(&second.0, second.3, answer(&blocks, &second));
+        assert_eq!(delivery, Delivery::Stale);
+        assert_eq!(ibd.on_blocks(&a, first.3, Vec::new()), Delivery::Stale);
+    }
+
+    #[test]
+    fn abort_falls_back_to_the_next_best_chain() {
+        let blocks = chain("genesis", 20, "aa");
+        let other = chain("genesis", 10, "bb");
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut network = Network::new();
+        let mut ibd = InitialSync::new();
+        ibd.add_peer(a);
+        ibd.on_headers(&a, headers(&blocks), U256::from(20), false);
+        ibd.add_peer(b);
+        ibd.on_headers(&b, headers(&blocks[..15]), U256::from(15), false);
+        ibd.add_peer(c);
+        ibd.on_headers(&c, headers(&other), U256::from(10), false);
+
+        let sent = network.schedule(&mut ibd, 0);
+        assert!(sent.iter().all(|(peer, ..)| *peer != c));
+
+        // both peers that have the invalid block are forgotten.
+        let mut aborted = ibd.abort(&blocks[5].header);
+        aborted.sort();
+        let mut expected = vec![a, b];
+        expected.sort();
+        assert_eq!(aborted, expected);
+        assert!(ibd.next_block().is_none());
+
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0, sent[0].1, sent[0].2), (c, 1, 10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alone_is_synced_after_the_grace_period() {
+        let mut ibd = InitialSync::new();
+        assert!(!ibd.is_synced());
+        tokio::time::advance(GRACE_PERIOD + Duration::from_secs(1)).await;
+        assert!(ibd.is_synced());
+
+        // a peer that is behind us doesn't make us sync.
+        let peer = PeerId::random();
+        ibd.add_peer(peer);
+        assert!(!ibd.is_synced());
+        ibd.on_headers(&peer, Vec::new(), U256::zero(), false);
+        let mut network = Network::new();
+        assert!(network.schedule(&mut ibd, 1).is_empty());
+        assert!(ibd.is_synced());
+    }
+
+    #[test]
+    fn restarted_headers_are_asked_again_a_few_times() {
+        let blocks = chain("genesis", 32, "aa");
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut network = Network::new();
+        let mut ibd = InitialSync::new();
+        for peer in [a, b] {
+            ibd.add_peer(peer);
+            ibd.on_headers(&peer, headers(&blocks), U256::from(32), false);
+        }
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent.len(), 2);
+        let (peer, from, ..) = sent[0];
+
+        // its blocks go to the other peer, and its headers are expected again.
+        assert!(ibd.restart_headers(&peer));
+        assert_eq!(ibd.headers_of(&peer), Some(&[][..]));
+        let other = if peer == a { b } else { a };
+        ibd.on_blocks_failed(&other, sent[1].3);
+        let sent = network.schedule(&mut ibd, 0);
+        assert_eq!(sent.len(), 1);
+        assert_eq!((sent[0].0, sent[0].1), (other, from));
+
+        for _ in 1..MAX_HEADER_RESTARTS {
+            assert!(ibd.restart_headers(&peer));
+        }
+        assert!(!ibd.restart_headers(&peer));
+        assert_eq!(ibd.headers_of(&peer), None);
+        assert!(!ibd.restart_headers(&PeerId::random()));
+    }
+}
@@ -0,0 +1,312 @@
+use super::pow::U256;
+use log::warn;
+use std::{collections::HashMap, path::Path};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{self, AsyncReadExt, AsyncWriteExt},
+};
+
+// The index starts with a header with the format version, followed by
+// fixed size records, one per block, where the position of the record
+// is the height of the block:
+//
+// | segment: u32 LE | offset: u64 LE | len: u32 LE | chain work: 32 bytes BE | hash: 64 bytes, zero padded |
+//
+// It only holds data derived from the block log, so a torn, stale or
+// outdated index is simply truncated and rebuilt from the log.
+const HEADER: &[u8; 8] = b"BLKIDX02";
+const RECORD_LEN: usize = 112;
+const HASH_LEN: usize = 64;
+
+// Where a block frame lives in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location {
+    pub segment: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl Location {
+    // position right after this frame.
+    pub fn end(&self) -> u64 {
+        self.offset + self.len as u64
+    }
+}
+
+struct Entry {
+    location: Location,
+    // accumulated work of the chain up to and including this block.
+    work: U256,
+    hash: String,
+}
+
+pub struct BlockIndex {
+    file: File,
+    // entries of every block, by height.
+    entries: Vec<Entry>,
+    heights: HashMap<String, u64>,
+}
+
+impl BlockIndex {
+    pub async fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .await?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+
+        let records = match buf.strip_prefix(HEADER) {
+            Some(records) => records,
+            None => {
+                if !buf.is_empty() {
+                    warn!("the block index has an unknown format, rebuilding it.");
+                }
+                file.set_len(0).await?;
+                file.write_all(HEADER).await?;
+                file.sync_data().await?;
+                &[]
+            }
+        };
+
+        let mut index = Self {
+            file,
+            entries: Vec::with_capacity(records.len() / RECORD_LEN),
+            heights: HashMap::new(),
+        };
+
+        for record in records.chunks_exact(RECORD_LEN) {
+            match decode_record(record) {
+                Some(entry) => index.insert(entry),
+                None => break,
+            }
+        }
+        // drop a torn or corrupted record at the end, if any.
+        if index.entries.len() * RECORD_LEN != records.len() {
+            index.truncate(index.len()).await?;
+        }
+
+        Ok(index)
+    }
+    pub fn len(&self) -> u64 {
+        self.entries.len() as u64
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn location(&self, height: u64) -> Option<Location> {
+        self.entries
+            .get(height as usize)
+            .map(|entry| entry.location)
+    }
+    pub fn height(&self, hash: &str) -> Option<u64> {
+        self.heights.get(hash).copied()
+    }
+    pub fn hash(&self, height: u64) -> Option<&str> {
+        self.entries
+            .get(height as usize)
+            .map(|entry| &entry.hash[..])
+    }
+    // accumulated work of the chain up to and including `height`.
+    pub fn work(&self, height: u64) -> Option<U256> {
+        self.entries.get(height as usize).map(|entry| entry.work)
+    }
+    pub fn last(&self) -> Option<Location> {
+        self.entries.last().map(|entry| entry.location)
+    }
+    pub async fn push(&mut self, location: Location, work: U256, hash: &str) -> io::Result<()> {
+        if hash.len() > HASH_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("block hash is longer than {HASH_LEN} bytes"),
+            ));
+        }
+        let mut work_bytes = [0; 32];
+        work.to_big_endian(&mut work_bytes);
+
+        let mut record = Vec::with_capacity(RECORD_LEN);
+        record.extend_from_slice(&location.segment.to_le_bytes());
+        record.extend_from_slice(&location.offset.to_le_bytes());
+        record.extend_from_slice(&location.len.to_le_bytes());
+        record.extend_from_slice(&work_bytes);
+        record.extend_from_slice(hash.as_bytes());
+        record.resize(RECORD_LEN, 0);
+
+        self.file.write_all(&record).await?;
+        self.file.sync_data().await?;
+        self.insert(Entry {
+            location,
+            work,
+            hash: hash.to_owned(),
+        });
+
+        Ok(())
+    }
+    // Keep only the first `len` entries.
+    pub async fn truncate(&mut self, len: u64) -> io::Result<()> {
+        for entry in self.entries.drain(len as usize..) {
+            self.heights.remove(&entry.hash);
+        }
+        self.file
+            .set_len((HEADER.len() + len as usize * RECORD_LEN) as u64)
+            .await?;
+
+        Ok(())
+    }
+    fn insert(&mut self, entry: Entry) {
+        self.heights.insert(entry.hash.clone(), self.len());
+        self.entries.push(entry);
+    }
+}
+
+fn decode_record(record: &[u8]) -> Option<Entry> {
+    let location = Location {
+        segment: u32::from_le_bytes(record[0..4].try_into().ok()?),
+        offset: u64::from_le_bytes(record[4..12].try_into().ok()?),
+        len: u32::from_le_bytes(record[12..16].try_into().ok()?),
+    };
+    let work = U256::from_big_endian(&record[16..48]);
+    let hash = &record[48..];
+    let hash_len = hash.iter().position(|b| *b == 0).unwrap_or(HASH_LEN);
+    let hash = std::str::from_utf8(&hash[..hash_len]).ok()?;
+
+    // a zeroed record is what a crash can leave behind.
+    if location.len == 0 || hash.is_empty() {
+        return None;
+    }
+
+    Some(Entry {
+        location,
+        work,
+        hash: hash.to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    static FILES: AtomicU32 = AtomicU32::new(0);
+
+    // a fresh path for a single test.
+    fn temp_path() -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "blockindex-{}-{}.dat",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn location(height: u64) -> Location {
+        Location {
+            segment: 0,
+            offset: 8 + height * 100,
+            len: 100,
+        }
+    }
+
+    async fn index_with(path: &Path, len: u64) -> BlockIndex {
+        let mut index = BlockIndex::open(path).await.unwrap();
+        for height in 0..len {
+            let work = U256::from(height + 1);
+            index
+                .push(location(height), work, &format!("hash{height}"))
+                .await
+                .unwrap();
+        }
+        index
+    }
+
+    #[tokio::test]
+    async fn reopen_keeps_every_entry() {
+        let path = temp_path();
+        drop(index_with(&path, 3).await);
+
+        let index = BlockIndex::open(&path).await.unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.location(1), Some(location(1)));
+        assert_eq!(index.last(), Some(location(2)));
+        assert_eq!(index.height("hash2"), Some(2));
+        assert_eq!(index.hash(0), Some("hash0"));
+        assert_eq!(index.work(2), Some(U256::from(3)));
+        assert_eq!(location(1).end(), location(2).offset);
+    }
+
+    #[tokio::test]
+    async fn torn_record_is_truncated() {
+        let path = temp_path();
+        drop(index_with(&path, 2).await);
+
+        let mut buf = std::fs::read(&path).unwrap();
+        let len = buf.len();
+        buf.extend_from_slice(&[1; RECORD_LEN / 2]);
+        std::fs::write(&path, &buf).unwrap();
+
+        let index = BlockIndex::open(&path).await.unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len as u64);
+
+        // a zeroed record, and everything after it, is dropped too.
+        let first = buf[HEADER.len()..HEADER.len() + RECORD_LEN].to_vec();
+        buf.truncate(len);
+        buf.extend_from_slice(&[0; RECORD_LEN]);
+        buf.extend_from_slice(&first);
+        std::fs::write(&path, &buf).unwrap();
+        drop(index);
+
+        let index = BlockIndex::open(&path).await.unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len as u64);
+    }
+
+    #[tokio::test]
+    async fn truncate_drops_later_entries() {
+        let path = temp_path();
+        let mut index = index_with(&path, 3).await;
+
+        index.truncate(1).await.unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.height("hash1"), None);
+        assert_eq!(index.location(1), None);
+
+        index
+            .push(location(1), U256::from(5), "other")
+            .await
+            .unwrap();
+        drop(index);
+
+        let index = BlockIndex::open(&path).await.unwrap();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.height("other"), Some(1));
+        assert_eq!(index.work(1), Some(U256::from(5)));
+    }
+
+    #[tokio::test]
+    async fn unknown_format_is_rebuilt() {
+        let path = temp_path();
+        std::fs::write(&path, b"BLKIDX01 some older records").unwrap();
+
+        let index = BlockIndex::open(&path).await.unwrap();
+        assert!(index.is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), HEADER);
+    }
+
+    #[tokio::test]
+    async fn long_hash_is_rejected() {
+        let path = temp_path();
+        let mut index = BlockIndex::open(&path).await.unwrap();
+
+        let hash = "a".repeat(HASH_LEN + 1);
+        assert!(index.push(location(0), U256::one(), &hash).await.is_err());
+        assert!(index.is_empty());
+    }
+}
@@ -0,0 +1,428 @@
+use super::{
+    error::{ChainError, Result},
+    state::Ledger,
+    transaction::Transaction,
+};
+use log::{debug, info};
+use speedy::Writable;
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+// bytes of transactions that the miner puts in a block, besides the coinbase.
+pub const MAX_TEMPLATE_SIZE: usize = 1_000_000;
+// transactions of a sender held at most. Each one is checked on top of
+// the ones before it, so this bounds the cost of accepting one.
+const MAX_SENDER_TRANSACTIONS: usize = 25;
+
+#[derive(Debug, Clone)]
+pub struct MempoolConfig {
+    // bytes of transactions held at most, the ones
+    // with the lowest fee rate are evicted beyond that.
+    pub max_size: usize,
+    // transactions are evicted once they waited that long.
+    pub max_age: Duration,
+}
+
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 10_000_000,
+            max_age: Duration::from_secs(3 * 60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    tx: Transaction,
+    hash: String,
+    // size of the encoded transaction, in bytes.
+    size: usize,
+    added: Instant,
+}
+
+impl Entry {
+    // fee per 1000 bytes.
+    fn fee_rate(&self) -> u64 {
+        self.tx.fee.saturating_mul(1000) / self.size.max(1) as u64
+    }
+}
+
+// Transactions waiting to be included in a block, checked against the
+// state at the tip of the chain. The transactions of a sender are kept
+// in the order they were accepted, and are only included or evicted in
+// that order: in the account model every one of them needs the nonce of
+// the one before it.
+#[derive(Debug, Default)]
+pub struct Mempool {
+    config: MempoolConfig,
+    // transactions by sender address, in the order they were accepted.
+    senders: HashMap<String, VecDeque<Entry>>,
+    // hashes of every transaction held.
+    hashes: HashSet<String>,
+    size: usize,
+}
+
+impl Mempool {
+    pub fn new(config: MempoolConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+    // number of transactions.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+    // bytes of transactions.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+    pub fn contains(&self, hash: &str) -> bool {
+        self.hashes.contains(hash)
+    }
+    // Accept `tx` if it is valid on top of `ledger` and of the transactions
+    // of the same sender that are already there. `height` is the height
+    // of the next block.
+    pub fn add(&mut self, tx: Transaction, ledger: &Ledger, height: u64) -> Result<()> {
+        let hash = tx.hash();
+
+        if self.contains(&hash) {
+            return Err(ChainError::DuplicateTransaction { tx: hash });
+        }
+        // mints have no sender, so they can't be signed either.
+        if !tx.verify() {
+            return Err(ChainError::InvalidSignature { height, tx: hash });
+        }
+        self.expire(ledger, height);
+
+        let sender = tx.sender_address();
+        let mut transactions = self.transactions_of(&sender);
+        if transactions.len() >= MAX_SENDER_TRANSACTIONS {
+            return Err(ChainError::TooManyTransactions { tx: hash });
+        }
+        transactions.push(tx.clone());
+        ledger.check_transactions(height, transactions)?;
+
+        let entry = Entry {
+            size: tx.write_to_vec().unwrap().len(),
+            tx,
+            hash: hash.clone(),
+            added: Instant::now(),
+        };
+        self.size += entry.size;
+        self.hashes.insert(hash.clone());
+        self.senders.entry(sender).or_default().push_back(entry);
+
+        self.evict();
+        if !self.contains(&hash) {
+            return Err(ChainError::MempoolFull { tx: hash });
+        }
+        info!("transaction {hash} added to the mempool.");
+
+        Ok(())
+    }
+    // Check every transaction again once the tip changed, the ones that
+    // were included in a block or conflict with it are removed, as well
+    // as the ones that expired. `height` is the height of the next block.
+    pub fn update(&mut self, ledger: &Ledger, height: u64) {
+        let senders: Vec<_> = self.senders.keys().cloned().collect();
+
+        for sender in senders {
+            self.retain_valid(&sender, ledger, height);
+        }
+        self.expire(ledger, height);
+    }
+    // The transactions with the highest fee rate, up to `max_size` bytes,
+    // in an order that can be included in a block.
+    pub fn select(&self, max_size: usize) -> Vec<Transaction> {
+        // the next transaction of every sender, by fee rate.
+        let mut heap: BinaryHeap<_> = self
+            .senders
+            .iter()
+            .filter_map(|(sender, queue)| Some((queue.front()?.fee_rate(), sender, 0)))
+            .collect();
+
+        let mut transactions = Vec::new();
+        let mut size = 0;
+
+        while let Some((_, sender, index)) = heap.pop() {
+            let queue = &self.senders[sender];
+            let entry = &queue[index];
+
+            // the next ones of this sender can't come without this one.
+            if size + entry.size > max_size {
+                continue;
+            }
+            size += entry.size;
+            transactions.push(entry.tx.clone());
+
+            if let Some(next) = queue.get(index + 1) {
+                heap.push((next.fee_rate(), sender, index + 1));
+            }
+        }
+
+        transactions
+    }
+    fn transactions_of(&self, sender: &str) -> Vec<Transaction> {
+        self.senders
+            .get(sender)
+            .map(|queue| queue.iter().map(|entry| entry.tx.clone()).collect())
+            .unwrap_or_default()
+    }
+    // Keep the transactions of `sender` that are still valid, in order.
+    fn retain_valid(&mut self, sender: &str, ledger: &Ledger, height: u64) {
+        let queue = match self.senders.remove(sender) {
+            Some(queue) => queue,
+            None => return,
+        };
+        // most of the time they all are, check them at once.
+        let all = queue.iter().map(|entry| entry.tx.clone()).collect();
+        if ledger.check_transactions(height, all).is_ok() {
+            self.senders.insert(sender.to_string(), queue);
+            return;
+        }
+        let mut kept = VecDeque::new();
+        let mut transactions = Vec::new();
+
+        for entry in queue {
+            transactions.push(entry.tx.clone());
+
+            if ledger
+                .check_transactions(height, transactions.clone())
+                .is_ok()
+            {
+                kept.push_back(entry);
+            } else {
+                debug!("transaction {} is not valid anymore.", entry.hash);
+                transactions.pop();
+                self.size -= entry.size;
+                self.hashes.remove(&entry.hash);
+            }
+        }
+        if !kept.is_empty() {
+            self.senders.insert(sender.to_string(), kept);
+        }
+    }
+    // Remove the transactions that waited too long, the ones
+    // of the same sender that needed them go with them.
+    fn expire(&mut self, ledger: &Ledger, height: u64) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+
+        for (sender, queue) in self.senders.iter_mut() {
+            // transactions are in the order they were accepted.
+            while let Some(entry) = queue.front() {
+                if now.duration_since(entry.added) < self.config.max_age {
+                    break;
+                }
+                debug!("transaction {} expired.", entry.hash);
+                self.size -= entry.size;
+                self.hashes.remove(&entry.hash);
+                queue.pop_front();
+                expired.push(sender.clone());
+            }
+        }
+        for sender in expired {
+            self.retain_valid(&sender, ledger, height);
+        }
+    }
+    // Evict the transactions with the lowest fee rate while the
+    // mempool is too big. Only the last transaction of a sender can
+    // be evicted, as the ones before it don't depend on it.
+    fn evict(&mut self) {
+        while self.size > self.config.max_size {
+            let sender = self
+                .senders
+                .iter()
+                .filter_map(|(sender, queue)| Some((queue.back()?.fee_rate(), sender)))
+                .min()
+                .map(|(_, sender)| sender.clone());
+
+            let sender = match sender {
+                Some(sender) => sender,
+                None => break,
+            };
+            let queue = self.senders.get_mut(&sender).unwrap();
+
+            if let Some(entry) = queue.pop_back() {
+                debug!("mempool is full, evicting transaction {}.", entry.hash);
+                self.size -= entry.size;
+                self.hashes.remove(&entry.hash);
+            }
+            if queue.is_empty() {
+                self.senders.remove(&sender);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{
+        block::Block,
+        state::LedgerKind,
+        transaction::{self, Output},
+    };
+    use libp2p::identity::ed25519::Keypair;
+
+    // an account ledger where every one of `keypairs` has 1000 coins.
+    fn funded(keypairs: &[&Keypair]) -> Ledger {
+        let transactions = keypairs
+            .iter()
+            .map(|keypair| Transaction::mint(transaction::address(&keypair.public()), 1000))
+            .collect();
+        let mut ledger = Ledger::new(LedgerKind::Account);
+        let mut genesis = Block::new(0, String::new(), transactions, 0x2000ffff);
+        genesis.header.state_root = ledger.state_root(&genesis).unwrap();
+        ledger.apply(&ledger.diff(&genesis).unwrap());
+        ledger
+    }
+
+    fn pay(keypair: &Keypair, nonce: u64, fee: u64) -> Transaction {
+        let output = Output {
+            recipient: "bb".repeat(32),
+            amount: 10,
+        };
+        Transaction::new(keypair, Vec::new(), vec![output], nonce, fee)
+    }
+
+    fn size(tx: &Transaction) -> usize {
+        tx.write_to_vec().unwrap().len()
+    }
+
+    #[test]
+    fn select_keeps_the_order_of_each_sender() {
+        let (alice, bob) = (Keypair::generate(), Keypair::generate());
+        let ledger = funded(&[&alice, &bob]);
+        let mut mempool = Mempool::new(MempoolConfig::default());
+
+        let (a0, a1, b0) = (pay(&alice, 0, 1), pay(&alice, 1, 100), pay(&bob, 0, 10));
+        for tx in [a0.clone(), a1.clone(), b0.clone()] {
+            mempool.add(tx, &ledger, 1).unwrap();
+        }
+        assert_eq!(mempool.len(), 3);
+        assert!(matches!(
+            mempool.add(a0.clone(), &ledger, 1),
+            Err(ChainError::DuplicateTransaction { .. })
+        ));
+        // a nonce gap is refused.
+        assert!(mempool.add(pay(&alice, 3, 1), &ledger, 1).is_err());
+
+        // the best fee of alice comes after her worst one.
+        let all = mempool.select(MAX_TEMPLATE_SIZE);
+        assert_eq!(all, [b0.clone(), a0.clone(), a1]);
+        assert!(ledger.check_transactions(1, all).is_ok());
+
+        // and not at all without it.
+        let room = size(&b0) + size(&a0) - 1;
+        assert_eq!(mempool.select(room), [b0]);
+    }
+
+    #[test]
+    fn full_mempool_evicts_the_lowest_fee_rate() {
+        let (alice, bob, carol) = (
+            Keypair::generate(),
+            Keypair::generate(),
+            Keypair::generate(),
+        );
+        let ledger = funded(&[&alice, &bob, &carol]);
+        let tx_size = size(&pay(&alice, 0, 1));
+        let mut mempool = Mempool::new(MempoolConfig {
+            max_size: tx_size * 2,
+            ..Default::default()
+        });
+
+        let (a0, a1) = (pay(&alice, 0, 50), pay(&alice, 1, 52));
+        mempool.add(a0.clone(), &ledger, 1).unwrap();
+        mempool.add(a1.clone(), &ledger, 1).unwrap();
+
+        // only the last transaction of a sender can go: alice's first
+        // one has the lowest fee rate, but her second one is evicted.
+        let b0 = pay(&bob, 0, 55);
+        mempool.add(b0.clone(), &ledger, 1).unwrap();
+        assert_eq!(mempool.len(), 2);
+        assert!(mempool.contains(&a0.hash()) && mempool.contains(&b0.hash()));
+        assert!(!mempool.contains(&a1.hash()));
+        assert!(mempool.size() <= tx_size * 2);
+
+        // a transaction that would be evicted right away is refused.
+        let c0 = pay(&carol, 0, 1);
+        assert!(matches!(
+            mempool.add(c0.clone(), &ledger, 1),
+            Err(ChainError::MempoolFull { .. })
+        ));
+        assert!(!mempool.contains(&c0.hash()));
+        assert_eq!(mempool.len(), 2);
+    }
+
+    #[test]
+    fn senders_are_limited() {
+        let alice = Keypair::generate();
+        let ledger = funded(&[&alice]);
+        let mut mempool = Mempool::new(MempoolConfig::default());
+
+        for nonce in 0..MAX_SENDER_TRANSACTIONS as u64 {
+            mempool.add(pay(&alice, nonce, 1), &ledger, 1).unwrap();
+        }
+        let next = pay(&alice, MAX_SENDER_TRANSACTIONS as u64, 1);
+        assert!(matches!(
+            mempool.add(next, &ledger, 1),
+            Err(ChainError::TooManyTransactions { .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_transactions_take_their_successors() {
+        let (alice, bob) = (Keypair::generate(), Keypair::generate());
+        let ledger = funded(&[&alice, &bob]);
+        let max_age = Duration::from_secs(60);
+        let mut mempool = Mempool::new(MempoolConfig {
+            max_age,
+            ..Default::default()
+        });
+
+        mempool.add(pay(&alice, 0, 1), &ledger, 1).unwrap();
+        tokio::time::advance(max_age / 2).await;
+        let (a1, b0) = (pay(&alice, 1, 1), pay(&bob, 0, 1));
+        mempool.add(a1.clone(), &ledger, 1).unwrap();
+        mempool.add(b0.clone(), &ledger, 1).unwrap();
+
+        mempool.update(&ledger, 1);
+        assert_eq!(mempool.len(), 3);
+
+        // the first one of alice expired, her second one can't go without it.
+        tokio::time::advance(max_age / 2).await;
+        mempool.update(&ledger, 1);
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&b0.hash()));
+        assert!(!mempool.contains(&a1.hash()));
+        assert_eq!(mempool.size(), size(&b0));
+    }
+
+    #[test]
+    fn update_drops_included_transactions() {
+        let alice = Keypair::generate();
+        let mut ledger = funded(&[&alice]);
+        let mut mempool = Mempool::new(MempoolConfig::default());
+        let (a0, a1) = (pay(&alice, 0, 1), pay(&alice, 1, 1));
+        mempool.add(a0.clone(), &ledger, 1).unwrap();
+        mempool.add(a1.clone(), &ledger, 1).unwrap();
+
+        let mut block = Block::new(1, String::new(), vec![a0.clone()], 0x2000ffff);
+        block.header.state_root = ledger.state_root(&block).unwrap();
+        ledger.apply(&ledger.diff(&block).unwrap());
+
+        mempool.update(&ledger, 2);
+        assert!(!mempool.contains(&a0.hash()));
+        assert!(mempool.contains(&a1.hash()));
+        assert_eq!(mempool.select(MAX_TEMPLATE_SIZE), [a1]);
+    }
+}
@@ -0,0 +1,125 @@
+use sha2::{Digest, Sha256};
+use speedy::{Readable, Writable};
+
+pub type Hash = [u8; 32];
+
+// Leaves and inner nodes are hashed with a different prefix, so that an
+// inner node can never be passed off as a leaf. When a level has an odd
+// number of nodes, the last one is carried up to the next level as is,
+// instead of being paired with a copy of itself.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+pub fn hash_leaf(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+pub fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Root of the tree whose leaves are the hashes in `leaves`,
+// all zeros for an empty tree.
+pub fn root(leaves: &[Hash]) -> Hash {
+    if leaves.is_empty() {
+        return Hash::default();
+    }
+    let mut level = leaves.to_vec();
+
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+// The hashes needed to go from a leaf up to the root.
+#[derive(Debug, Clone, PartialEq, Eq, Writable, Readable)]
+pub struct MerkleProof {
+    // siblings from the bottom of the tree to the top. The levels where
+    // the node was carried up as is have no sibling.
+    pub steps: Vec<ProofStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Writable, Readable)]
+pub struct ProofStep {
+    pub side: Side,
+    // hex hash of the sibling.
+    pub hash: String,
+}
+
+// The side of the sibling, relative to the node on the path to the root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Writable, Readable)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+// Proof that the leaf at `index` is part of the tree of `leaves`.
+pub fn proof(leaves: &[Hash], mut index: usize) -> Option<MerkleProof> {
+    if index >= leaves.len() {
+        return None;
+    }
+    let mut steps = Vec::new();
+    let mut level = leaves.to_vec();
+
+    while level.len() > 1 {
+        let step = if index % 2 == 1 {
+            Some(ProofStep {
+                side: Side::Left,
+                hash: hex::encode(level[index - 1]),
+            })
+        } else {
+            level.get(index + 1).map(|hash| ProofStep {
+                side: Side::Right,
+                hash: hex::encode(hash),
+            })
+        };
+        steps.extend(step);
+
+        level = next_level(&level);
+        index /= 2;
+    }
+
+    Some(MerkleProof { steps })
+}
+
+// If `proof` leads from `leaf` to `root`.
+pub fn verify(root: &Hash, leaf: &Hash, proof: &MerkleProof) -> bool {
+    let mut node = *leaf;
+
+    for step in &proof.steps {
+        let sibling = match decode_hash(&step.hash) {
+            Some(hash) => hash,
+            None => return false,
+        };
+        node = match step.side {
+            Side::Left => hash_node(&sibling, &node),
+            Side::Right => hash_node(&node, &sibling),
+        };
+    }
+
+    node == *root
+}
+
+// A hash from its hex form.
+pub fn decode_hash(hash: &str) -> Option<Hash> {
+    hex::decode(hash).ok()?.try_into().ok()
+}
+
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [odd] => *odd,
+            _ => unreachable!(),
+        })
+        .collect()
+}
@@ -0,0 +1,136 @@
+use super::block::Block;
+use log::{debug, info};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+};
+use tokio::{sync::oneshot, time::Instant};
+
+// hashes that a thread computes between two updates of the shared counter.
+const HASHES_PER_UPDATE: u64 = 1024;
+
+// Mines blocks on a pool of OS threads, away from the async runtime.
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize,
+}
+
+impl Default for Miner {
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(threads)
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+        }
+    }
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+    // Start mining `template` in the background. The nonce space is
+    // split between the threads: thread `i` tries the nonces
+    // `i`, `i + threads`, `i + 2 * threads`...
+    pub fn start(&self, template: Block) -> MiningJob {
+        let stop = Arc::new(AtomicBool::new(false));
+        let hashes = Arc::new(AtomicU64::new(0));
+        let (s, found) = oneshot::channel();
+        // only the first thread to find a nonce can take the sender.
+        let s = Arc::new(std::sync::Mutex::new(Some(s)));
+
+        debug!(
+            "mining block {} on {} threads.",
+            template.header.id, self.threads
+        );
+
+        for i in 0..self.threads {
+            let mut block = template.clone();
+            let stop = stop.clone();
+            let hashes = hashes.clone();
+            let s = s.clone();
+            let step = self.threads as u64;
+
+            thread::spawn(move || {
+                block.header.nonce = i as u64;
+                let mut count = 0;
+
+                while !stop.load(Ordering::Relaxed) {
+                    block.hash = block.calculate_hash();
+                    count += 1;
+
+                    if block.meets_target() {
+                        stop.store(true, Ordering::Relaxed);
+                        if let Some(s) = s.lock().unwrap().take() {
+                            let _ = s.send(block);
+                        }
+                        break;
+                    }
+                    if count == HASHES_PER_UPDATE {
+                        hashes.fetch_add(count, Ordering::Relaxed);
+                        count = 0;
+                    }
+                    block.header.nonce = block.header.nonce.wrapping_add(step);
+                }
+                hashes.fetch_add(count, Ordering::Relaxed);
+            });
+        }
+
+        MiningJob {
+            template,
+            stop,
+            hashes,
+            started: Instant::now(),
+            found,
+        }
+    }
+}
+
+// A block being mined. Dropping the job stops the threads.
+pub struct MiningJob {
+    template: Block,
+    stop: Arc<AtomicBool>,
+    hashes: Arc<AtomicU64>,
+    started: Instant,
+    found: oneshot::Receiver<Block>,
+}
+
+impl MiningJob {
+    // the block that is being mined, without a valid nonce.
+    pub fn template(&self) -> &Block {
+        &self.template
+    }
+    pub fn cancel(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+    // hashes per second since the job started.
+    pub fn hashrate(&self) -> f64 {
+        let secs = self.started.elapsed().as_secs_f64();
+        self.hashes.load(Ordering::Relaxed) as f64 / secs.max(f64::EPSILON)
+    }
+    // Wait for a thread to find a valid nonce,
+    // `None` if the job was cancelled before that.
+    pub async fn found(&mut self) -> Option<Block> {
+        let block = (&mut self.found).await.ok()?;
+
+        info!(
+            "block mined in {}s with nonce: \"{}\" at {:.0} H/s",
+            self.started.elapsed().as_secs(),
+            block.header.nonce,
+            self.hashrate()
+        );
+
+        Some(block)
+    }
+}
+
+impl Drop for MiningJob {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
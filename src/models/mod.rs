@@ -1,5 +1,7 @@
+// the `Readable` derive of speedy trips this lint on structs with 4 fields or less.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 // use crossbeam_channel::{unbounded, Receiver, Sender};
-use libp2p::{gossipsub::IdentTopic, identity::Keypair, PeerId};
 use once_cell::sync::Lazy;
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
@@ -7,11 +9,29 @@ use self::p2p::Event;
 
 // pub static LOCAL_KEY: Lazy<Keypair> = Lazy::new(|| Keypair::generate_ed25519());
 // pub static LOCAL_PEER_ID: Lazy<PeerId> = Lazy::new(|| PeerId::from(LOCAL_KEY.public()));
-pub static TOPIC: Lazy<IdentTopic> = Lazy::new(|| IdentTopic::new("gossip"));
-
 pub static mut CHANNEL: Lazy<(UnboundedSender<Event>, UnboundedReceiver<Event>)> =
-    Lazy::new(|| mpsc::unbounded_channel::<Event>());
+    Lazy::new(mpsc::unbounded_channel::<Event>);
 
+pub mod account;
 pub mod block;
 pub mod blockchain;
+pub mod error;
+pub mod genesis;
+pub mod gossip;
+pub mod ibd;
+pub mod index;
+pub mod mempool;
+pub mod merkle;
+pub mod miner;
+pub mod node_key;
 pub mod p2p;
+pub mod pow;
+pub mod reputation;
+pub mod reward;
+pub mod state;
+pub mod store;
+pub mod sync;
+pub mod transaction;
+pub mod tree;
+pub mod utxo;
+pub mod wallet;
@@ -0,0 +1,58 @@
+use libp2p::identity::{ed25519, Keypair};
+use log::info;
+use std::path::Path;
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt, ErrorKind},
+};
+
+// DER prefix of an ed25519 private key in PKCS#8 v1 (RFC 8410),
+// followed by the 32 bytes of the secret key.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+// Load the key of the node, that its PeerId derives from, from the PKCS#8
+// file at `path`, or generate a new ed25519 one and store it there if there
+// is none. Both ed25519 and RSA keys are supported, e.g. the ones made with
+// `openssl genpkey -algorithm ed25519 -outform der`.
+pub async fn load_or_generate(path: impl AsRef<Path>) -> io::Result<Keypair> {
+    let path = path.as_ref();
+
+    match fs::read(path).await {
+        Ok(bytes) => decode(bytes),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let keypair = ed25519::Keypair::generate();
+
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            // only readable by us, anyone with it can impersonate the node.
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            let mut file = options.open(path).await?;
+            file.write_all(&encode(&keypair)).await?;
+            file.sync_all().await?;
+            info!("generated a new node key in {}", path.display());
+
+            Ok(Keypair::Ed25519(keypair))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn decode(mut bytes: Vec<u8>) -> io::Result<Keypair> {
+    let keypair = match bytes.strip_prefix(&ED25519_PKCS8_PREFIX[..]) {
+        Some(secret) => ed25519::SecretKey::from_bytes(secret.to_vec())
+            .map(|secret| Keypair::Ed25519(secret.into())),
+        None => Keypair::rsa_from_pkcs8(&mut bytes),
+    };
+
+    keypair.map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+}
+
+fn encode(keypair: &ed25519::Keypair) -> Vec<u8> {
+    [&ED25519_PKCS8_PREFIX[..], keypair.secret().as_ref()].concat()
+}
@@ -1,38 +1,73 @@
-use crate::models::{block::Block, blockchain, TOPIC};
+use crate::models::{
+    block::{Block, BlockHeader},
+    blockchain::{BlockStatus, Blockchain, ChainEvent},
+    error::ChainError,
+    gossip::{BlockMessage, Message, Topics, TxMessage},
+    ibd::{Delivery, InitialSync},
+    mempool::{Mempool, MAX_TEMPLATE_SIZE},
+    miner::{Miner, MiningJob},
+    reputation::{Misbehaviour, Reputation},
+    sync::{
+        self, SyncBehaviour, SyncEvent, SyncRequest, SyncResponse, MAX_BLOCKS, MAX_BLOCKS_SIZE,
+        MAX_HEADERS,
+    },
+    transaction::{self, Transaction},
+    wallet::Wallet,
+};
 use async_std::io;
 use futures::prelude::*;
+#[cfg(feature = "mdns")]
+use libp2p::mdns::{MdnsConfig, MdnsEvent, TokioMdns};
 use libp2p::{
     core::upgrade,
     futures::StreamExt,
-    gossipsub::{Gossipsub, GossipsubConfig, GossipsubEvent, MessageAuthenticity, TopicHash},
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, MessageAcceptance,
+        MessageAuthenticity, MessageId, PeerScoreThresholds,
+    },
+    identify,
     identity::Keypair,
     kad::{
         record::Key, store::MemoryStore, AddProviderOk, GetClosestPeersOk, Kademlia, KademliaEvent,
         PeerRecord, PutRecordOk, QueryResult, Quorum, Record,
     },
-    mdns::{MdnsEvent, TokioMdns},
     mplex,
+    multiaddr::Protocol,
     noise::NoiseAuthenticated,
-    swarm::{SwarmBuilder, SwarmEvent},
+    request_response::{RequestId, RequestResponseMessage},
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        SwarmBuilder, SwarmEvent,
+    },
     tcp::{self, GenTcpConfig},
     Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport,
 };
-use log::{debug, info, warn};
-use speedy::Readable;
+use log::{debug, error, info, warn};
+use speedy::Writable;
+use std::{collections::HashMap, time::Duration};
 use tokio::{
-    io::AsyncWriteExt,
     select,
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    time::Instant,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+    },
+    time::{self, Instant},
 };
 
-pub struct ChainResponse {
-    pub blocks: Vec<u8>,
-    // pub receiver: String,
-}
+// how often the bans that expired are lifted.
+const BAN_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+// protocol version announced with identify.
+const IDENTIFY_PROTOCOL: &str = "/blockchain/id/1";
+
+// discovers peers on the local network, see `NetworkConfig::mdns`.
+#[cfg(feature = "mdns")]
+type Mdns = TokioMdns;
+#[cfg(not(feature = "mdns"))]
+type Mdns = libp2p::swarm::dummy::Behaviour;
 
 pub enum Event {
-    BlockMined(Vec<u8>),
+    BlockMined(Block),
     Liebe,
 }
 
@@ -42,7 +77,11 @@ pub enum Event {
 pub struct AppBehaviour {
     pub gossipsub: Gossipsub,
     pub kademlia: Kademlia<MemoryStore>,
-    // pub mdns: TokioMdns,
+    // tells the peers our listen addresses, and us theirs, for kademlia.
+    pub identify: identify::Behaviour,
+    pub sync: SyncBehaviour,
+    // only enabled with `NetworkConfig::mdns`.
+    pub mdns: Toggle<Mdns>,
 }
 
 pub struct P2P {
@@ -50,15 +89,64 @@ pub struct P2P {
     pub local_key: PeerId,
     pub s: UnboundedSender<Event>,
     pub r: UnboundedReceiver<Event>,
+    pub chain: Blockchain,
+    // the gossipsub topics of the network of the chain.
+    pub topics: Topics,
+    pub miner: Miner,
+    // the block being mined, if any.
+    pub job: Option<MiningJob>,
+    pub wallet: Wallet,
+    // where the rewards of the blocks we mine are paid,
+    // the address of the wallet by default.
+    pub miner_address: String,
+    // transactions waiting to be included in a block.
+    pub mempool: Mempool,
+    // the download of the chain from the peers, we don't mine before it is over.
+    pub ibd: InitialSync,
+    // the sync requests waiting for a response, by id.
+    requests: HashMap<RequestId, SyncRequest>,
+    pub network: NetworkConfig,
+    // misbehaving peers are banned, see `Reputation::load` to persist the bans.
+    pub reputation: Reputation,
+    chain_events: broadcast::Receiver<ChainEvent>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    // peers added to the routing table of kademlia on startup, as
+    // multiaddrs ending with their PeerId, e.g. /ip4/1.2.3.4/tcp/4001/p2p/[peer id].
+    pub bootnodes: Vec<Multiaddr>,
+    // peers found by kademlia are dialed until we have that many.
+    pub target_peers: usize,
+    // how often the routing table is refreshed with a kademlia bootstrap.
+    pub bootstrap_interval: Duration,
+    // discover the peers of the local network with mDNS, they are added to
+    // kademlia and gossipsub. Needs the `mdns` feature.
+    pub mdns: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            bootnodes: Vec::new(),
+            target_peers: 8,
+            bootstrap_interval: Duration::from_secs(5 * 60),
+            mdns: false,
+        }
+    }
 }
 
 impl P2P {
-    pub async fn new() -> Self {
+    // `keypair` is the identity of the node, see `node_key::load_or_generate`.
+    pub async fn new(
+        chain: Blockchain,
+        miner: Miner,
+        wallet: Wallet,
+        keypair: Keypair,
+        network: NetworkConfig,
+    ) -> Self {
         let (s, r) = mpsc::unbounded_channel::<Event>();
 
-        // let mut bytes = std::fs::read("private2.pk8").unwrap();
-        // let keypair = Keypair::rsa_from_pkcs8(&mut bytes).unwrap();
-        let keypair = Keypair::generate_ed25519();
         let local_key = PeerId::from(keypair.public());
 
         let transport_config = GenTcpConfig::new().port_reuse(true);
@@ -76,27 +164,52 @@ impl P2P {
         let message_authenticity = MessageAuthenticity::Signed(keypair.clone());
 
         // Peer discovery protocols.
-        let mdns = TokioMdns::new(Default::default()).unwrap();
+        let mdns = Toggle::from(new_mdns(network.mdns));
 
         // let kademilia_config =
         //     KademliaConfig::default().set_protocol_names(vec![Cow::from(b"demian".to_owned())]);
-        let kademlia = Kademlia::new(local_key, MemoryStore::new(local_key));
+        let mut kademlia = Kademlia::new(local_key, MemoryStore::new(local_key));
+        for address in &network.bootnodes {
+            match peer_id_of(address) {
+                Some(peer) => {
+                    let mut address = address.clone();
+                    address.pop();
+                    kademlia.add_address(&peer, address);
+                }
+                None => warn!("ignoring bootnode {address} without a PeerId"),
+            }
+        }
+        let identify = identify::Behaviour::new(identify::Config::new(
+            IDENTIFY_PROTOCOL.to_string(),
+            keypair.public(),
+        ));
 
-        let gossipsub_config = GossipsubConfig::default();
+        // messages are only forwarded once we checked them, see `on_message`.
+        let gossipsub_config = GossipsubConfigBuilder::default()
+            .validate_messages()
+            .build()
+            .expect("could not configure gossipsub");
         let mut gossipsub = Gossipsub::new(message_authenticity, gossipsub_config)
             .expect("could not create gossipsub");
 
+        let topics = Topics::new(chain.genesis_hash());
         gossipsub
-            .subscribe(&TOPIC)
-            .expect("could not subscribe to topic");
+            .with_peer_score(topics.peer_score_params(), PeerScoreThresholds::default())
+            .expect("could not enable gossipsub peer scoring");
+        for topic in topics.all() {
+            gossipsub
+                .subscribe(topic)
+                .expect("could not subscribe to topic");
+        }
 
         // Create a Swarm to manage peers and events
         let mut swarm = {
-            // let mdns = TokioMdns::new(Default::default()).unwrap();
             let behaviour = AppBehaviour {
                 gossipsub,
-                // mdns,
                 kademlia,
+                identify,
+                sync: sync::new_behaviour(),
+                mdns,
             };
             SwarmBuilder::new(transport, behaviour, local_key)
                 // We want the connection background tasks to be spawned
@@ -119,6 +232,18 @@ impl P2P {
             local_key,
             s,
             r,
+            chain_events: chain.subscribe(),
+            chain,
+            topics,
+            miner,
+            job: None,
+            miner_address: wallet.address(),
+            wallet,
+            mempool: Mempool::default(),
+            ibd: InitialSync::new(),
+            requests: HashMap::new(),
+            network,
+            reputation: Reputation::default(),
         }
     }
 
@@ -127,17 +252,8 @@ impl P2P {
         // let (s, r) = unbounded();
         let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
 
-        // Dial the peer identified by the multi-address given as the second
-        // command-line argument, if any.
-        // cargo run /ip4/127.0.0.1/tcp/[port]
-        if let Some(addr) = std::env::args().nth(1) {
-            let remote: Multiaddr = addr.parse().unwrap();
-            self.swarm.dial(remote).unwrap();
-            println!("Dialed {}", addr);
-        }
-
         let message =
-            "Welcome! type \"ls peers/blockchain\" to list, and \"block [data]\" to send a block."
+            "Welcome! type \"tx [address] [amount] [fee]\" to send coins, and \"block\" to mine them."
                 .to_string();
         let lines: String = message.chars().map(|_| "-").collect();
 
@@ -151,55 +267,139 @@ impl P2P {
         println!("           ||     ||");
         println!("\n");
 
-        self.swarm
-            .behaviour_mut()
-            .kademlia
-            .get_closest_peers(self.local_key.to_bytes());
+        for peer in self.reputation.banned() {
+            self.swarm.behaviour_mut().gossipsub.blacklist_peer(&peer);
+        }
+
+        // the first tick is right away.
+        let mut bootstrap = time::interval(self.network.bootstrap_interval);
+        let mut bans = time::interval(BAN_EXPIRY_INTERVAL);
 
         // Listen to events on the P2P network, and user input (for now).
         loop {
             select! {
+                _ = bootstrap.tick() => {
+                    if let Err(e) = self.swarm.behaviour_mut().kademlia.bootstrap() {
+                        debug!("could not bootstrap kademlia: {e:?}");
+                    }
+                    self.dial_peers();
+                }
+                _ = bans.tick() => {
+                    for peer in self.reputation.expire().await {
+                        self.swarm.behaviour_mut().gossipsub.remove_blacklisted_peer(&peer);
+                    }
+                }
                 event = self.r.recv() => {
                     match event.unwrap() {
                         Event::Liebe => {
                             info!("-------------------LIEBE");
                         },
-                        Event::BlockMined(mut blocks) => {
-                            let rcv_chain =
-                            Vec::<Block>::read_from_buffer(&mut blocks[..]).unwrap();
-
-                            info!("validating chain with the new block... {:#?}", rcv_chain);
-
-                            let now = Instant::now();
-                            let is_valid = Block::validate_all(&rcv_chain).is_ok();
-
-                            if is_valid {
-                                info!("chain is valid and took {}ms to validate", now.elapsed().as_millis());
-                                debug!("chain is valid");
-                                let mut file = blockchain::open().await;
-                                match file.write_all(&blocks[..]).await {
-                                    Ok(_) => {
-                                        info!(
-                                            "The new blockchain was written in {}μs with success",
-                                            now.elapsed().as_micros()
-                                        );
-                                    },
-                                    Err(_) => warn!("error trying to write new blockchain to the file")
-                                }
-                            } else {
-                                warn!("chain is invalid");
-                            }
+                        Event::BlockMined(block) => {
+                            let _ = self.on_block(block, None).await;
                         }
                     };
                 }
+                block = mined(&mut self.job) => {
+                    self.job = None;
+                    if let Some(block) = block {
+                        self.on_block_mined(block).await;
+                    }
+                }
+                event = self.chain_events.recv() => {
+                    match event {
+                        Ok(ChainEvent::BlockConnected(_)) | Err(RecvError::Lagged(_)) => {
+                            self.mempool.update(self.chain.ledger(), self.chain.len());
+                            self.on_tip_changed().await;
+                        }
+                        Ok(ChainEvent::BlockDisconnected(block)) => {
+                            // its transactions go back to the mempool, if still valid.
+                            for tx in block.transactions.into_iter().filter(|tx| !tx.is_mint()) {
+                                let _ = self.mempool.add(tx, self.chain.ledger(), self.chain.len());
+                            }
+                            self.on_tip_changed().await;
+                        }
+                        Err(RecvError::Closed) => {}
+                    }
+                }
                 line = stdin.select_next_some() => {
                     let args = line.unwrap();
                     let mut args = args.split(' ');
 
                     match args.next() {
                         Some("ls_blocks") => {
-                            let chain = blockchain::read_all().await.unwrap();
-                            println!("{:#?}", chain);
+                            match self.chain.read_all().await {
+                                Ok(chain) => println!("{:#?}", chain),
+                                Err(e) => eprintln!("could not read the blockchain: {e}"),
+                            }
+                        },
+                        Some("proof") => {
+                            let height = args.next().and_then(|height| height.parse().ok());
+                            let index = args.next().and_then(|index| index.parse().ok());
+                            let (height, index) = match (height, index) {
+                                (Some(height), Some(index)) => (height, index),
+                                _ => {
+                                    eprintln!("Expected a block height and a transaction index");
+                                    continue;
+                                }
+                            };
+                            match self.chain.merkle_proof(height, index).await {
+                                Ok(proof) => println!("{:#?}", proof),
+                                Err(e) => eprintln!("could not build the proof: {e}"),
+                            }
+                        },
+                        Some("address") => println!("{}", self.wallet.address()),
+                        Some("balance") => println!("{}", self.wallet.balance(self.chain.ledger())),
+                        Some("tx") => {
+                            let recipient = match args.next() {
+                                Some(address) if transaction::is_valid_address(address) => address,
+                                _ => {
+                                    eprintln!("Expected the address of the recipient");
+                                    continue;
+                                }
+                            };
+                            let amount = match args.next().map(str::parse::<u64>) {
+                                Some(Ok(amount)) => amount,
+                                _ => {
+                                    eprintln!("Expected an amount");
+                                    continue;
+                                }
+                            };
+                            let fee = args.next().and_then(|fee| fee.parse().ok()).unwrap_or(0);
+
+                            let tx = match self.wallet.send(self.chain.ledger(), &self.mempool, recipient.to_string(), amount, fee) {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    eprintln!("could not send the transaction: {e}");
+                                    continue;
+                                }
+                            };
+                            let hash = tx.hash();
+                            match self.mempool.add(tx.clone(), self.chain.ledger(), self.chain.len()) {
+                                Ok(()) => {
+                                    println!("transaction {hash} is in the mempool");
+                                    self.publish_transaction(&tx);
+                                    self.wallet.sent(tx);
+                                }
+                                Err(e) => eprintln!("could not send the transaction: {e}"),
+                            }
+                        },
+                        Some("mempool") => println!(
+                            "{} transactions, {} bytes",
+                            self.mempool.len(),
+                            self.mempool.size()
+                        ),
+                        Some("block") if !self.ibd.is_synced() => {
+                            eprintln!("still downloading the chain from peers, try again later");
+                        }
+                        Some("block") => self.start_mining().await,
+                        Some("mining") => match &self.job {
+                            Some(job) => println!(
+                                "mining block {} on {} threads at {:.0} H/s",
+                                job.template().header.id,
+                                self.miner.threads(),
+                                job.hashrate()
+                            ),
+                            None => println!("not mining"),
                         },
                         Some("ls_peers") => {
                             // let peers: Vec<(&PeerId, Vec<&TopicHash>)> = self.swarm
@@ -295,41 +495,40 @@ impl P2P {
                             // self.swarm.behaviour_mut().kademlia.add_address(&self.local_key, address);
                         },
                     SwarmEvent::IncomingConnection { .. } => {},
-                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
                         if endpoint.is_dialer() {
                             info!("Connection established - peerId: {peer_id}");
                         }
+                        if self.reputation.is_banned(&peer_id) {
+                            debug!("refusing the connection of the banned peer {peer_id}");
+                            let _ = self.swarm.disconnect_peer_id(peer_id);
+                        } else if num_established.get() == 1 {
+                            self.ibd.add_peer(peer_id);
+                            let locator = self.chain.locator();
+                            self.request(peer_id, SyncRequest::GetHeaders { locator });
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                        self.ibd.remove_peer(&peer_id);
+                        self.reputation.on_disconnected(&peer_id);
+                        self.schedule();
                     }
                     SwarmEvent::Behaviour(AppBehaviourEvent::Gossipsub(GossipsubEvent::Message {
                         message,
+                        message_id,
                         propagation_source: peer,
-                        ..
-                    })) => {
-                            // get the last 7 characters of the peerID
-                            let peer = peer.to_string();
-                            let truncated_peer_id = peer[peer.len() - 7..].to_string();
-                            println!(
-                                "\n{truncated_peer_id}: {}",
-                                String::from_utf8_lossy(&message.data)
-                            );
-                        },
-                    // will notify RoutingUpdated if kademilia_add_address is successfull.
-                    // SwarmEvent::Behaviour(AppBehaviourEvent::Mdns(MdnsEvent::Discovered(list))) => {
-                    //     for (peer_id, multiaddr) in list {
-                    //         info!("mDNS discovered a new peer: {peer_id} multiaddr: {multiaddr}");
-                    //         // self.swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer_id);
-                    //         self.swarm.behaviour_mut().kademlia.add_address(&peer_id, multiaddr);
-                    //     }
-                    // },
-                    // SwarmEvent::Behaviour(AppBehaviourEvent::Mdns(MdnsEvent::Expired(list))) => {
-                    //     for (peer_id, _multiaddr) in list {
-                    //         info!("mDNS discover peer has expired: {}", peer_id);
-                    //         self.swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer_id);
-                    //     }
-                    // },
+                    })) => self.on_message(peer, message_id, message).await,
+                    SwarmEvent::Behaviour(AppBehaviourEvent::Sync(event)) => self.on_sync(event).await,
+                    #[cfg(feature = "mdns")]
+                    SwarmEvent::Behaviour(AppBehaviourEvent::Mdns(event)) => self.on_mdns(event),
+                    SwarmEvent::Behaviour(AppBehaviourEvent::Identify(identify::Event::Received { peer_id, info })) => {
+                        for address in info.listen_addrs {
+                            self.swarm.behaviour_mut().kademlia.add_address(&peer_id, address);
+                        }
+                    }
                     SwarmEvent::Behaviour(AppBehaviourEvent::Kademlia(KademliaEvent::RoutingUpdated{ peer, addresses, .. })) => {
-                        info!("routing updated with {peer}");
-                        info!("addresses known of this peer {:#?}", addresses);
+                        debug!("routing updated with {peer} at {addresses:?}");
+                        self.dial_peers();
                     },
                     SwarmEvent::Dialing(peer_id) => info!("Dialing {peer_id}"),
                     SwarmEvent::Behaviour(AppBehaviourEvent::Kademlia(KademliaEvent::OutboundQueryCompleted { result, ..})) => {
@@ -382,4 +581,559 @@ impl P2P {
             };
         }
     }
+
+    // Peers found or gone on the local network. The ones found are dialed
+    // by kademlia, and gossipsub keeps them as explicit peers, that it
+    // always sends messages to.
+    #[cfg(feature = "mdns")]
+    fn on_mdns(&mut self, event: MdnsEvent) {
+        let behaviour = self.swarm.behaviour_mut();
+
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer, address) in list {
+                    debug!("mDNS discovered {peer} at {address}");
+                    behaviour.kademlia.add_address(&peer, address);
+                    behaviour.gossipsub.add_explicit_peer(&peer);
+                }
+            }
+            MdnsEvent::Expired(list) => {
+                for (peer, _) in list {
+                    // it may still be there at another address.
+                    if !behaviour
+                        .mdns
+                        .as_ref()
+                        .is_some_and(|mdns| mdns.has_node(&peer))
+                    {
+                        debug!("mDNS peer {peer} expired");
+                        behaviour.gossipsub.remove_explicit_peer(&peer);
+                    }
+                }
+            }
+        }
+    }
+    // Dial peers of the routing table of kademlia that we are not
+    // connected to, until we have `target_peers` of them.
+    fn dial_peers(&mut self) {
+        let missing = self
+            .network
+            .target_peers
+            .saturating_sub(self.swarm.connected_peers().count());
+        if missing == 0 {
+            return;
+        }
+        let known: Vec<PeerId> = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| *entry.node.key.preimage())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let peers: Vec<_> = known
+            .into_iter()
+            .filter(|peer| !self.swarm.is_connected(peer))
+            .take(missing)
+            .collect();
+
+        for peer in peers {
+            let opts = DialOpts::peer_id(peer)
+                .condition(PeerCondition::NotDialing)
+                .build();
+            if let Err(e) = self.swarm.dial(opts) {
+                debug!("could not dial {peer}: {e}");
+            }
+        }
+    }
+    // Start mining a block with the best transactions of the mempool
+    // on top of the chain, replacing the block that was being mined, if any.
+    async fn start_mining(&mut self) {
+        let transactions = self.mempool.select(MAX_TEMPLATE_SIZE);
+
+        match self
+            .chain
+            .block_template(&self.miner_address, transactions)
+            .await
+        {
+            Ok(template) => self.job = Some(self.miner.start(template)),
+            Err(e) => error!("could not create a new block: {e}"),
+        }
+    }
+    // The block being mined doesn't extend the chain anymore,
+    // so start again on top of the new tip.
+    async fn on_tip_changed(&mut self) {
+        match &self.job {
+            Some(job) if job.template().header.previous_hash != self.chain.latest().hash => {
+                job.cancel();
+            }
+            _ => return,
+        };
+        info!("the chain tip changed, mining on top of the new tip.");
+        self.start_mining().await;
+    }
+    // A gossip message relayed by `peer`. Gossipsub only forwards the ones
+    // we accept, and lowers the score of the peers that relay the ones we
+    // reject, which are invalid for every node.
+    async fn on_message(&mut self, peer: PeerId, id: MessageId, message: GossipsubMessage) {
+        let acceptance = match self.topics.decode(&message.topic, &message.data) {
+            Some(Message::Block(BlockMessage::Block(block))) => {
+                match self.on_block(block, Some(peer)).await {
+                    Ok(BlockStatus::Duplicate) => MessageAcceptance::Ignore,
+                    Ok(_) => MessageAcceptance::Accept,
+                    Err(e) if e.is_local() => MessageAcceptance::Ignore,
+                    Err(_) => MessageAcceptance::Reject,
+                }
+            }
+            Some(Message::Tx(TxMessage::Transaction(tx))) => match self.on_transaction(tx) {
+                Ok(()) => MessageAcceptance::Accept,
+                Err(ChainError::InvalidSignature { .. }) => {
+                    self.punish(peer, Misbehaviour::InvalidTransaction).await;
+                    MessageAcceptance::Reject
+                }
+                // it may be valid on top of a block we don't have yet.
+                Err(_) => MessageAcceptance::Ignore,
+            },
+            None => {
+                warn!("malformed message from {peer} on {}", message.topic);
+                self.punish(peer, Misbehaviour::MalformedMessage).await;
+                MessageAcceptance::Reject
+            }
+        };
+
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(&id, &peer, acceptance)
+        {
+            debug!("could not report the validation of message {id}: {e:?}");
+        }
+    }
+    // A block received from `peer`, or mined by us. If its parent is
+    // unknown, it is fetched from the same peer, and so on until the
+    // block connects to our chain.
+    async fn on_block(
+        &mut self,
+        block: Block,
+        peer: Option<PeerId>,
+    ) -> Result<BlockStatus, ChainError> {
+        let hash = block.hash.clone();
+        let parent = block.header.previous_hash.clone();
+        let now = Instant::now();
+
+        let result = self.chain.process_block(block).await;
+        match (&result, peer) {
+            (Ok(BlockStatus::Orphan), Some(peer)) => {
+                debug!("fetching the parent {parent} of block {hash} from {peer}.");
+                self.request(peer, SyncRequest::GetBlock { hash: parent });
+            }
+            (Ok(status), _) => info!(
+                "block {hash} was processed in {}μs: {status:?}",
+                now.elapsed().as_micros()
+            ),
+            (Err(e), _) if e.is_local() => error!("could not process block {hash}: {e}"),
+            (Err(e), peer) => {
+                warn!("block {hash} is invalid: {e}");
+                if let Some(peer) = peer {
+                    self.punish(peer, Misbehaviour::InvalidBlock).await;
+                }
+            }
+        }
+        result
+    }
+    // Send a sync request to `peer`, its response or failure comes
+    // back to `on_sync` with the same id.
+    fn request(&mut self, peer: PeerId, request: SyncRequest) -> RequestId {
+        send_request(&mut self.swarm, &mut self.requests, peer, request)
+    }
+    async fn on_sync(&mut self, event: SyncEvent) {
+        match event {
+            SyncEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                // the channel is dropped, the peer sees its request fail.
+                if !self.reputation.on_request(&peer) {
+                    self.punish(peer, Misbehaviour::Spam).await;
+                    return;
+                }
+                match respond(&self.chain, request).await {
+                    Ok(response) => {
+                        if self
+                            .swarm
+                            .behaviour_mut()
+                            .sync
+                            .send_response(channel, response)
+                            .is_err()
+                        {
+                            debug!("{peer} gave up on its sync request.");
+                        }
+                    }
+                    Err(ChainError::GenesisMismatch { found, .. }) => {
+                        warn!("{peer} is on another network, with the genesis block {found:?}.");
+                        self.disconnect(peer);
+                    }
+                    Err(e) => error!("could not answer a sync request: {e}"),
+                }
+            }
+            SyncEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => {
+                let request = match self.requests.remove(&request_id) {
+                    Some(request) => request,
+                    None => return,
+                };
+                match (request, response) {
+                    (SyncRequest::GetBlock { hash }, SyncResponse::Block(Some(block)))
+                        if block.hash == hash =>
+                    {
+                        let _ = self.on_block(block, Some(peer)).await;
+                    }
+                    (SyncRequest::GetBlock { hash }, SyncResponse::Block(None)) => {
+                        debug!("{peer} doesn't know the block {hash}")
+                    }
+                    (SyncRequest::GetTip, SyncResponse::Tip { height, hash }) => {
+                        debug!("the tip of {peer} is {hash}, at height {height}.")
+                    }
+                    (SyncRequest::GetHeaders { locator }, SyncResponse::Headers(headers)) => {
+                        self.on_headers(peer, &locator, headers).await
+                    }
+                    (SyncRequest::GetBlocks { .. }, SyncResponse::Blocks(blocks)) => {
+                        self.on_blocks(peer, request_id, blocks).await
+                    }
+                    // including a block other than the one asked for.
+                    (request, _) => {
+                        warn!("{peer} sent a response that doesn't answer {request:?}");
+                        self.drop_peer(peer, Misbehaviour::MalformedMessage).await;
+                        self.schedule();
+                    }
+                }
+            }
+            // the blocks asked for are requested from other peers.
+            SyncEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                let request = self.requests.remove(&request_id);
+                debug!("sync request {request:?} to {peer} failed: {error}");
+
+                match request {
+                    Some(SyncRequest::GetHeaders { .. }) => self.ibd.on_headers_failed(&peer),
+                    Some(SyncRequest::GetBlocks { .. }) => {
+                        self.ibd.on_blocks_failed(&peer, request_id)
+                    }
+                    _ => {}
+                }
+                self.schedule();
+            }
+            SyncEvent::InboundFailure { peer, error, .. } => {
+                debug!("could not answer the sync request of {peer}: {error}")
+            }
+            SyncEvent::ResponseSent { .. } => {}
+        }
+    }
+    // Headers of the chain of `peer`, in response to a request with
+    // `locator`, checked on top of the ones it already sent. The next
+    // ones are asked for while the responses are full.
+    async fn on_headers(&mut self, peer: PeerId, locator: &[String], headers: Vec<BlockHeader>) {
+        let branch = match self.ibd.headers_of(&peer) {
+            Some(branch) => branch,
+            None => {
+                debug!("ignoring unexpected headers from {peer}");
+                return;
+            }
+        };
+        // the headers fork from a block of our chain that we sent, or
+        // from the fork point of the headers that we already checked.
+        let continued = !branch.is_empty();
+
+        let work = match self.chain.validate_headers(branch, &headers).await {
+            Ok(work) => work,
+            // our chain was reorganized since we asked, they are asked
+            // again from where it is now.
+            Err(ChainError::UnknownBlock { hash }) if continued || locator.contains(&hash) => {
+                debug!(
+                    "the headers of {peer} fork from {hash}, which is not in our chain anymore."
+                );
+                self.restart_headers(peer);
+                return;
+            }
+            Err(e) if e.is_local() && !matches!(e, ChainError::UnknownBlock { .. }) => {
+                warn!("could not check the headers of {peer}: {e}");
+                self.ibd.on_headers_failed(&peer);
+                self.schedule();
+                return;
+            }
+            Err(e) => {
+                warn!("{peer} sent invalid headers: {e}");
+                self.drop_peer(peer, Misbehaviour::InvalidHeaders).await;
+                return;
+            }
+        };
+        let full = headers.len() as u64 >= MAX_HEADERS;
+
+        match headers.last() {
+            Some(last) if full => {
+                let mut locator = vec![last.calculate_hash()];
+                locator.extend(self.chain.locator());
+
+                self.request(peer, SyncRequest::GetHeaders { locator });
+            }
+            _ => debug!("received the headers of {peer}."),
+        }
+        self.ibd.on_headers(&peer, headers, work, full);
+        self.schedule();
+    }
+    // Blocks of the chain being downloaded, connected as soon as
+    // the ones before them are.
+    async fn on_blocks(&mut self, peer: PeerId, request: RequestId, blocks: Vec<Block>) {
+        match self.ibd.on_blocks(&peer, request, blocks) {
+            Delivery::Accepted => {}
+            Delivery::Stale => {
+                debug!("ignoring stale blocks from {peer}");
+                return;
+            }
+            // an honest peer whose chain was reorganized since it sent
+            // its headers, they are asked again.
+            Delivery::Mismatch => {
+                debug!("the blocks of {peer} don't match its headers anymore.");
+                self.restart_headers(peer);
+                return;
+            }
+            Delivery::Invalid => {
+                warn!("{peer} sent invalid blocks.");
+                self.drop_peer(peer, Misbehaviour::InvalidBlock).await;
+                self.schedule();
+                return;
+            }
+        }
+        let height = self.chain.len();
+
+        while let Some(block) = self.ibd.next_block() {
+            let hash = block.hash.clone();
+            let header = block.header.clone();
+
+            match self.chain.process_block(block).await {
+                Err(e) if e.is_local() => {
+                    error!("could not process block {hash}: {e}");
+                    break;
+                }
+                Err(e) => {
+                    warn!("block {hash} of the chain being downloaded is invalid: {e}");
+
+                    for peer in self.ibd.abort(&header) {
+                        self.punish(peer, Misbehaviour::InvalidBlock).await;
+                    }
+                    break;
+                }
+                Ok(_) => {}
+            }
+        }
+        if self.chain.len() > height {
+            info!(
+                "downloaded the chain up to height {}.",
+                self.chain.len() - 1
+            );
+        }
+        self.schedule();
+    }
+    // Ask `peer` for its headers again from scratch, as they don't fit
+    // our chain or its blocks anymore, unless we did it too often already.
+    fn restart_headers(&mut self, peer: PeerId) {
+        if self.ibd.restart_headers(&peer) {
+            let locator = self.chain.locator();
+            self.request(peer, SyncRequest::GetHeaders { locator });
+        } else {
+            debug!("giving up on the headers of {peer}, they changed too often.");
+        }
+        self.schedule();
+    }
+    // Ask idle peers for the next blocks to download, once
+    // every peer sent its headers.
+    fn schedule(&mut self) {
+        let synced = self.ibd.is_synced();
+        let (swarm, requests) = (&mut self.swarm, &mut self.requests);
+
+        self.ibd.schedule(self.chain.work(), |peer, from, count| {
+            send_request(
+                swarm,
+                requests,
+                peer,
+                SyncRequest::GetBlocks { from, count },
+            )
+        });
+        if !synced && self.ibd.is_synced() {
+            info!(
+                "the chain is downloaded, at height {}.",
+                self.chain.len() - 1
+            );
+        }
+    }
+    // Stop syncing with `peer`, that misbehaved.
+    async fn drop_peer(&mut self, peer: PeerId, misbehaviour: Misbehaviour) {
+        self.ibd.remove_peer(&peer);
+        self.punish(peer, misbehaviour).await;
+    }
+    // Stop talking to `peer`, that follows the chain of another network.
+    // It didn't misbehave, so it isn't banned, but it is of no use.
+    fn disconnect(&mut self, peer: PeerId) {
+        self.ibd.remove_peer(&peer);
+        self.swarm.behaviour_mut().kademlia.remove_peer(&peer);
+
+        let _ = self.swarm.disconnect_peer_id(peer);
+    }
+    // Lower the reputation of `peer`, it is banned if it falls too low.
+    async fn punish(&mut self, peer: PeerId, misbehaviour: Misbehaviour) {
+        if self.reputation.penalize(&peer, misbehaviour).await {
+            self.enforce_ban(peer);
+        }
+    }
+    // Cut `peer` off until its ban expires.
+    fn enforce_ban(&mut self, peer: PeerId) {
+        self.ibd.remove_peer(&peer);
+
+        let behaviour = self.swarm.behaviour_mut();
+        behaviour.gossipsub.blacklist_peer(&peer);
+        behaviour.kademlia.remove_peer(&peer);
+
+        let _ = self.swarm.disconnect_peer_id(peer);
+    }
+    // A transaction received from the network.
+    fn on_transaction(&mut self, tx: Transaction) -> Result<(), ChainError> {
+        let hash = tx.hash();
+        let result = self.mempool.add(tx, self.chain.ledger(), self.chain.len());
+
+        match &result {
+            Ok(()) | Err(ChainError::DuplicateTransaction { .. }) => {}
+            Err(e) => warn!("rejected transaction {hash}: {e}"),
+        }
+        result
+    }
+    fn publish_transaction(&mut self, tx: &Transaction) {
+        let message = TxMessage::Transaction(tx.clone());
+
+        if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(
+            self.topics.transactions.clone(),
+            message.write_to_vec().unwrap(),
+        ) {
+            warn!("could not publish transaction {}: {e:?}", tx.hash());
+        }
+    }
+    async fn on_block_mined(&mut self, block: Block) {
+        let message = BlockMessage::Block(block.clone());
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(self.topics.blocks.clone(), message.write_to_vec().unwrap())
+        {
+            warn!("could not publish the mined block: {e:?}");
+        }
+
+        if let Err(e) = self.s.send(Event::BlockMined(block)) {
+            error!(
+                "Failed to send event to the network that the block was mined. Reason: {}",
+                e
+            );
+        };
+    }
+}
+
+#[cfg(feature = "mdns")]
+fn new_mdns(enabled: bool) -> Option<Mdns> {
+    if !enabled {
+        return None;
+    }
+    match TokioMdns::new(MdnsConfig::default()) {
+        Ok(mdns) => Some(mdns),
+        Err(e) => {
+            warn!("could not start mDNS: {e}");
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "mdns"))]
+fn new_mdns(enabled: bool) -> Option<Mdns> {
+    if enabled {
+        warn!("mDNS is not available, it needs the `mdns` feature.");
+    }
+    None
+}
+
+// The PeerId at the end of `address`, if any.
+pub fn peer_id_of(address: &Multiaddr) -> Option<PeerId> {
+    match address.iter().last()? {
+        Protocol::P2p(hash) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    }
+}
+
+// Wait for the block of `job` to be mined, forever if there is no job.
+async fn mined(job: &mut Option<MiningJob>) -> Option<Block> {
+    match job {
+        Some(job) => job.found().await,
+        None => future::pending().await,
+    }
+}
+
+// Send `request` to `peer`, and keep it in `requests` until its
+// response or failure comes back with the same id.
+fn send_request(
+    swarm: &mut Swarm<AppBehaviour>,
+    requests: &mut HashMap<RequestId, SyncRequest>,
+    peer: PeerId,
+    request: SyncRequest,
+) -> RequestId {
+    let id = swarm
+        .behaviour_mut()
+        .sync
+        .send_request(&peer, request.clone());
+
+    requests.insert(id, request);
+    id
+}
+
+// The answer to a sync request of a peer.
+async fn respond(chain: &Blockchain, request: SyncRequest) -> Result<SyncResponse, ChainError> {
+    let response = match request {
+        SyncRequest::GetBlock { hash } => {
+            SyncResponse::Block(chain.get_block_by_hash(&hash).await?)
+        }
+        SyncRequest::GetTip => SyncResponse::Tip {
+            height: chain.len() - 1,
+            hash: chain.latest().hash.clone(),
+        },
+        SyncRequest::GetHeaders { locator } => {
+            SyncResponse::Headers(chain.headers_after(&locator, MAX_HEADERS).await?)
+        }
+        SyncRequest::GetBlocks { from, count } => {
+            let mut blocks = chain.blocks_range(from, count.min(MAX_BLOCKS)).await?;
+            let mut size = 0;
+
+            // at least one block, so that the peer always makes progress.
+            if let Some(i) = blocks.iter().position(|block| {
+                size += block.write_to_vec().unwrap().len();
+                size > MAX_BLOCKS_SIZE
+            }) {
+                blocks.truncate(i.max(1));
+            }
+            SyncResponse::Blocks(blocks)
+        }
+    };
+
+    Ok(response)
 }
@@ -0,0 +1,306 @@
+use super::{
+    block::BlockHeader,
+    error::{ChainError, Result},
+};
+use serde::Deserialize;
+
+pub use self::u256::U256;
+
+// the code generated by the macro is not ours to lint.
+#[allow(clippy::all)]
+mod u256 {
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+
+// Consensus parameters of the proof of work. Targets are 256-bit numbers,
+// a block hash is valid when it is lower or equal to the target. They are
+// stored in blocks in the same compact form as bitcoin's "nBits":
+// the highest byte is the size of the target in bytes,
+// and the 3 lower bytes are its most significant bytes.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PowParams {
+    // target of the genesis block.
+    pub bits: u32,
+    // the easiest target allowed.
+    pub limit_bits: u32,
+    // desired time between blocks, in milliseconds.
+    pub target_block_time: u64,
+    // number of blocks between two retargets.
+    pub retarget_interval: u64,
+    // the target can change at most by this factor in a single retarget.
+    pub max_adjustment: u64,
+}
+
+impl Default for PowParams {
+    fn default() -> Self {
+        Self {
+            // 4 leading hex zeros.
+            bits: 0x1f00ffff,
+            // 2 leading hex zeros.
+            limit_bits: 0x2000ffff,
+            target_block_time: 10_000,
+            retarget_interval: 10,
+            max_adjustment: 4,
+        }
+    }
+}
+
+impl PowParams {
+    pub fn check(&self) -> Result<()> {
+        if self.retarget_interval < 2 {
+            return Err(ChainError::InvalidParams(
+                "retarget_interval must be at least 2".to_string(),
+            ));
+        }
+        if self.max_adjustment < 1 || self.target_block_time < 1 {
+            return Err(ChainError::InvalidParams(
+                "max_adjustment and target_block_time must be positive".to_string(),
+            ));
+        }
+        // the longest window a retarget clamps to, in milliseconds.
+        let window = self
+            .target_block_time
+            .checked_mul(self.retarget_interval - 1)
+            .and_then(|expected| expected.checked_mul(self.max_adjustment));
+        if window.is_none() {
+            return Err(ChainError::InvalidParams(
+                "target_block_time * retarget_interval * max_adjustment is too large".to_string(),
+            ));
+        }
+        // a zero target can't be met, and has no work.
+        if from_compact(self.bits).is_zero() || self.limit().is_zero() {
+            return Err(ChainError::InvalidParams(
+                "bits and limit_bits must decode to a positive target".to_string(),
+            ));
+        }
+        // a retarget multiplies a target by up to `max_adjustment`.
+        if self
+            .limit()
+            .checked_mul(U256::from(self.max_adjustment))
+            .is_none()
+        {
+            return Err(ChainError::InvalidParams(
+                "limit_bits is too easy for max_adjustment".to_string(),
+            ));
+        }
+        if from_compact(self.bits) > self.limit() {
+            return Err(ChainError::InvalidParams(
+                "the genesis target is easier than the limit".to_string(),
+            ));
+        }
+        Ok(())
+    }
+    pub fn limit(&self) -> U256 {
+        from_compact(self.limit_bits)
+    }
+    // If the block at `height` starts a new retarget window.
+    pub fn is_retarget_height(&self, height: u64) -> bool {
+        height > 0 && height.is_multiple_of(self.retarget_interval)
+    }
+    // The target that the block after `previous` must have. Only the
+    // headers are needed. On retarget heights, `window_start` must be the
+    // block at `previous.id + 1 - retarget_interval`, it is ignored otherwise.
+    pub fn next_bits(&self, previous: &BlockHeader, window_start: Option<&BlockHeader>) -> u32 {
+        let window_start = match window_start {
+            // a window that doesn't start before `previous`, e.g. of a chain
+            // that doesn't start at the genesis block, can't be measured.
+            Some(header) if self.is_retarget_height(previous.id + 1) && header.id < previous.id => {
+                header
+            }
+            _ => return previous.bits,
+        };
+
+        let expected = self.target_block_time * (previous.id - window_start.id);
+        let actual = previous
+            .timestamp
+            .saturating_sub(window_start.timestamp)
+            .clamp(
+                expected / self.max_adjustment,
+                expected * self.max_adjustment,
+            );
+
+        // divide first, targets are big enough for the precision loss
+        // to not matter, and this can't overflow.
+        let target = from_compact(previous.bits) / expected * actual;
+
+        // a target too small to be divided by `expected` would become 0.
+        to_compact(target.clamp(U256::one(), self.limit()))
+    }
+}
+
+pub fn from_compact(bits: u32) -> U256 {
+    let size = bits >> 24;
+    let mantissa = bits & 0x007f_ffff;
+
+    if size <= 3 {
+        U256::from(mantissa >> (8 * (3 - size)))
+    } else {
+        U256::from(mantissa) << (8 * (size as usize - 3))
+    }
+}
+
+pub fn to_compact(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u32()
+    };
+
+    // the 0x00800000 bit is a sign bit, move to a bigger size
+    // instead of producing a negative target.
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+
+    mantissa | (size as u32) << 24
+}
+
+// The expected number of hashes needed to meet the target of `bits`,
+// which is 2^256 / (target + 1).
+pub fn work(bits: u32) -> U256 {
+    let target = from_compact(bits);
+
+    // 2^256 doesn't fit in 256 bits, but it is the same as
+    // (2^256 - target - 1) / (target + 1) + 1.
+    (!target / (target + U256::one())) + U256::one()
+}
+
+// The hash of a block as a number, if it is a valid hex sha256.
+pub fn hash_to_u256(hash: &str) -> Option<U256> {
+    let bytes = hex::decode(hash).ok()?;
+
+    if bytes.len() != 32 {
+        return None;
+    }
+    Some(U256::from_big_endian(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(id: u64, timestamp: u64, bits: u32) -> BlockHeader {
+        BlockHeader {
+            id,
+            previous_hash: String::new(),
+            timestamp,
+            nonce: 0,
+            bits,
+            merkle_root: String::new(),
+            state_root: String::new(),
+        }
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        for bits in [
+            0x1f00ffff, 0x2000ffff, 0x1d00ffff, 0x1b0404cb, 0x03123456, 0x01010000,
+        ] {
+            assert_eq!(to_compact(from_compact(bits)), bits, "{bits:#x}");
+        }
+        for target in [
+            U256::one(),
+            U256::from(0x1234_5678u64),
+            from_compact(0x1f00ffff) / 7,
+            U256::MAX >> 1,
+        ] {
+            let rounded = from_compact(to_compact(target));
+            // only the most significant bytes are kept.
+            assert!(
+                rounded <= target && target - rounded <= target >> 15,
+                "{target:#x}"
+            );
+        }
+        // the sign bit moves the mantissa to a bigger size.
+        assert_eq!(to_compact(U256::from(0x80)), 0x02008000);
+        assert_eq!(from_compact(0x02008000), U256::from(0x80));
+    }
+
+    #[test]
+    fn retarget_is_clamped() {
+        let params = PowParams {
+            bits: 0x1e00ffff,
+            limit_bits: 0x1f00ffff,
+            target_block_time: 1000,
+            retarget_interval: 10,
+            max_adjustment: 4,
+        };
+        let target = from_compact(params.bits);
+        let start = header(0, 0, params.bits);
+        let expected = 9 * params.target_block_time;
+        let next = |elapsed| params.next_bits(&header(9, elapsed, params.bits), Some(&start));
+
+        assert_eq!(next(expected), to_compact(target / expected * expected));
+        assert_eq!(
+            next(expected * 2),
+            to_compact(target / expected * (expected * 2))
+        );
+        // blocks 100 times too fast: the target is only divided by 4.
+        assert_eq!(
+            next(expected / 100),
+            to_compact(target / expected * (expected / 4))
+        );
+        assert_eq!(next(0), next(expected / 4));
+        // blocks 100 times too slow: the target is only multiplied by 4.
+        assert_eq!(
+            next(expected * 100),
+            to_compact(target / expected * (expected * 4))
+        );
+        // and never easier than the limit.
+        let easy = header(9, expected * 100, params.limit_bits);
+        assert_eq!(
+            params.next_bits(&easy, Some(&start)),
+            to_compact(params.limit())
+        );
+        // nor 0.
+        let hard = header(9, 0, 0x01010000);
+        assert_eq!(params.next_bits(&hard, Some(&start)), 0x01010000);
+        // no retarget outside of retarget heights.
+        let early = header(4, 1, params.bits);
+        assert_eq!(params.next_bits(&early, Some(&start)), params.bits);
+        // nor with a window that doesn't start before the previous block.
+        let previous = header(9, expected, params.bits);
+        for start in [header(9, 0, params.bits), header(12, 0, params.bits)] {
+            assert_eq!(params.next_bits(&previous, Some(&start)), params.bits);
+        }
+    }
+
+    #[test]
+    fn check_rejects_zero_and_overflowing_params() {
+        assert!(PowParams::default().check().is_ok());
+        let zero = PowParams {
+            bits: 0x01003456,
+            ..Default::default()
+        };
+        assert!(zero.check().is_err());
+        let zero_limit = PowParams {
+            bits: 0x01003456,
+            limit_bits: 0,
+            ..Default::default()
+        };
+        assert!(zero_limit.check().is_err());
+        let overflow = PowParams {
+            bits: 0x2000ffff,
+            limit_bits: 0x207fffff,
+            ..Default::default()
+        };
+        assert!(overflow.check().is_err());
+        let too_long = PowParams {
+            target_block_time: u64::MAX / 2,
+            ..Default::default()
+        };
+        assert!(too_long.check().is_err());
+    }
+
+    #[test]
+    fn work_is_inverse_to_the_target() {
+        // 2^256 / (0xffff << 232 + 1) and 2^256 / (0xffff << 224 + 1).
+        assert_eq!(work(0x2000ffff), U256::from(256));
+        assert_eq!(work(0x1f00ffff), U256::from(65537));
+    }
+}
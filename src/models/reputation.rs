@@ -0,0 +1,245 @@
+use chrono::Utc;
+use libp2p::PeerId;
+use log::{info, warn};
+use speedy::{Readable, Writable};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{self, ErrorKind},
+    time::Instant,
+};
+
+// a peer whose score falls to this is banned.
+const BAN_THRESHOLD: i64 = -100;
+// how long a peer stays banned.
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+// the score of a peer goes back up by a point every that long, so
+// that an honest peer isn't banned for mistakes made over a long time.
+const RECOVERY_INTERVAL: Duration = Duration::from_secs(60);
+// sync requests a peer may send within `REQUEST_WINDOW`, the
+// next ones count as spam.
+const MAX_REQUESTS: u32 = 100;
+const REQUEST_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    // a block that doesn't validate, or that doesn't match the header it
+    // announced, including the blocks of a chain downloaded from the peer.
+    InvalidBlock,
+    // headers that don't link up or don't meet their target.
+    InvalidHeaders,
+    // a transaction with a bad signature.
+    InvalidTransaction,
+    // a gossip message that doesn't decode.
+    MalformedMessage,
+    // more sync requests than `MAX_REQUESTS` within `REQUEST_WINDOW`.
+    Spam,
+}
+
+impl Misbehaviour {
+    // points taken from the score of the peer. None of them bans a peer
+    // on its own, so that an honest peer that relayed one bad block, or
+    // that we misjudged once, isn't banned for it.
+    fn penalty(self) -> i64 {
+        match self {
+            Self::InvalidBlock | Self::InvalidHeaders => 50,
+            Self::MalformedMessage => 20,
+            Self::InvalidTransaction => 10,
+            Self::Spam => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Score {
+    value: i64,
+    updated: Instant,
+    // start of the current window of sync requests, and their number.
+    requests: (Instant, u32),
+}
+
+impl Default for Score {
+    fn default() -> Self {
+        let now = Instant::now();
+
+        Self {
+            value: 0,
+            updated: now,
+            requests: (now, 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Writable, Readable)]
+struct Ban {
+    peer: String,
+    // unix timestamp in milliseconds.
+    until: u64,
+}
+
+// The reputation of the peers. Every peer starts at 0 and loses points
+// when it misbehaves, see `Misbehaviour`, and gets them back slowly.
+// Once a peer reaches `BAN_THRESHOLD` it is banned for `BAN_DURATION`:
+// it is disconnected and its connections are refused until then. The
+// bans are written to a file, so that they outlive a restart.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    scores: HashMap<PeerId, Score>,
+    // banned peers, until a unix timestamp in milliseconds.
+    bans: HashMap<PeerId, u64>,
+    // where the bans are persisted, if anywhere.
+    path: Option<PathBuf>,
+}
+
+impl Reputation {
+    // Load the bans stored in `path`, the ones that expired are dropped.
+    // They are saved there from now on.
+    pub async fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let bans = match fs::read(path).await {
+            Ok(bytes) => Vec::<Ban>::read_from_buffer(&bytes)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let now = now();
+        let bans: HashMap<_, _> = bans
+            .into_iter()
+            .filter(|ban| ban.until > now)
+            .filter_map(|ban| Some((ban.peer.parse().ok()?, ban.until)))
+            .collect();
+
+        if !bans.is_empty() {
+            info!("loaded {} banned peers from {}", bans.len(), path.display());
+        }
+
+        Ok(Self {
+            scores: HashMap::new(),
+            bans,
+            path: Some(path.to_path_buf()),
+        })
+    }
+    pub fn is_banned(&self, peer: &PeerId) -> bool {
+        self.bans.get(peer).is_some_and(|until| *until > now())
+    }
+    // The banned peers, to be refused by the network behaviours.
+    pub fn banned(&self) -> Vec<PeerId> {
+        let now = now();
+
+        self.bans
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+    // Lower the score of `peer`. Returns true if it is banned from now on.
+    pub async fn penalize(&mut self, peer: &PeerId, misbehaviour: Misbehaviour) -> bool {
+        if self.is_banned(peer) {
+            return false;
+        }
+        let score = self.scores.entry(*peer).or_default();
+        recover(score);
+        score.value -= misbehaviour.penalty();
+        warn!(
+            "{peer} misbehaved: {misbehaviour:?}, its score is {}",
+            score.value
+        );
+
+        if score.value > BAN_THRESHOLD {
+            return false;
+        }
+        self.ban(peer, BAN_DURATION).await;
+        true
+    }
+    // Count a sync request of `peer`, false if it sent too many of them lately.
+    pub fn on_request(&mut self, peer: &PeerId) -> bool {
+        let score = self.scores.entry(*peer).or_default();
+        let (start, count) = &mut score.requests;
+
+        if start.elapsed() > REQUEST_WINDOW {
+            *start = Instant::now();
+            *count = 0;
+        }
+        *count += 1;
+        *count <= MAX_REQUESTS
+    }
+    pub async fn ban(&mut self, peer: &PeerId, duration: Duration) {
+        self.scores.remove(peer);
+        self.bans.insert(*peer, now() + duration.as_millis() as u64);
+        info!("banned {peer} for {}s", duration.as_secs());
+
+        self.save().await;
+    }
+    // Lift the bans that expired, and return the peers they were on.
+    pub async fn expire(&mut self) -> Vec<PeerId> {
+        let now = now();
+        let expired: Vec<_> = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        if !expired.is_empty() {
+            for peer in &expired {
+                self.bans.remove(peer);
+                info!("the ban of {peer} expired");
+            }
+            self.save().await;
+        }
+        expired
+    }
+    // Forget a peer that disconnected, unless it has points to win back.
+    pub fn on_disconnected(&mut self, peer: &PeerId) {
+        if let Some(score) = self.scores.get_mut(peer) {
+            recover(score);
+            if score.value == 0 {
+                self.scores.remove(peer);
+            }
+        }
+    }
+    // Write the bans to the file, in a new file first that replaces
+    // the old one, so that a crash doesn't leave half of it.
+    async fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let bans: Vec<_> = self
+            .bans
+            .iter()
+            .map(|(peer, until)| Ban {
+                peer: peer.to_base58(),
+                until: *until,
+            })
+            .collect();
+        let tmp = path.with_extension("tmp");
+
+        let result = async {
+            fs::write(&tmp, bans.write_to_vec().unwrap()).await?;
+            fs::rename(&tmp, path).await
+        };
+        if let Err(e) = result.await {
+            warn!("could not save the bans to {}: {e}", path.display());
+        }
+    }
+}
+
+// Give back the points a peer earned since its score was updated.
+fn recover(score: &mut Score) {
+    let points = (score.updated.elapsed().as_secs() / RECOVERY_INTERVAL.as_secs()) as i64;
+
+    if points > 0 {
+        score.value = (score.value + points).min(0);
+        score.updated += RECOVERY_INTERVAL * points as u32;
+    }
+}
+
+fn now() -> u64 {
+    Utc::now().timestamp_millis() as u64
+}
@@ -0,0 +1,39 @@
+use super::error::{ChainError, Result};
+use serde::Deserialize;
+
+// How many new coins every block can mint, on top of the fees of its
+// transactions, both paid to the miner by the coinbase transaction.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RewardParams {
+    // subsidy of the first blocks.
+    pub subsidy: u64,
+    // the subsidy is halved every `halving_interval` blocks.
+    pub halving_interval: u64,
+}
+
+impl Default for RewardParams {
+    fn default() -> Self {
+        Self {
+            subsidy: 50,
+            halving_interval: 210_000,
+        }
+    }
+}
+
+impl RewardParams {
+    pub fn check(&self) -> Result<()> {
+        if self.halving_interval < 1 {
+            return Err(ChainError::InvalidParams(
+                "halving_interval must be positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+    // The new coins that the block at `height` can mint.
+    pub fn subsidy(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval.max(1);
+
+        self.subsidy.checked_shr(halvings as u32).unwrap_or(0)
+    }
+}
@@ -0,0 +1,246 @@
+use super::{
+    account::{AccountDiff, AccountState},
+    block::Block,
+    error::{ChainError, Result},
+    store::{decode_frame, encode_frame},
+    transaction::Transaction,
+    utxo::{UtxoDiff, UtxoSet},
+};
+use log::{info, warn};
+use serde::Deserialize;
+use speedy::{Readable, Writable};
+use std::{io::SeekFrom, path::Path};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+// How the state of a chain is modelled, chosen once in its genesis spec.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedgerKind {
+    // transactions spend outputs of earlier transactions.
+    #[default]
+    Utxo,
+    // transactions move coins between account balances.
+    Account,
+}
+
+// The state of the chain at its tip.
+#[derive(Debug, Clone)]
+pub enum Ledger {
+    Utxo(UtxoSet),
+    Account(AccountState),
+}
+
+// The changes of a block to the ledger.
+#[derive(Debug, Clone, Writable, Readable)]
+pub enum StateDiff {
+    Utxo(UtxoDiff),
+    Account(AccountDiff),
+}
+
+impl StateDiff {
+    // hash of the block.
+    pub fn hash(&self) -> &str {
+        match self {
+            Self::Utxo(diff) => &diff.hash,
+            Self::Account(diff) => &diff.hash,
+        }
+    }
+    pub fn kind(&self) -> LedgerKind {
+        match self {
+            Self::Utxo(_) => LedgerKind::Utxo,
+            Self::Account(_) => LedgerKind::Account,
+        }
+    }
+}
+
+impl Ledger {
+    pub fn new(kind: LedgerKind) -> Self {
+        match kind {
+            LedgerKind::Utxo => Self::Utxo(UtxoSet::new()),
+            LedgerKind::Account => Self::Account(AccountState::new()),
+        }
+    }
+    pub fn kind(&self) -> LedgerKind {
+        match self {
+            Self::Utxo(_) => LedgerKind::Utxo,
+            Self::Account(_) => LedgerKind::Account,
+        }
+    }
+    pub fn balance(&self, address: &str) -> u64 {
+        match self {
+            Self::Utxo(utxos) => utxos.balance(address),
+            Self::Account(accounts) => accounts.get(address).balance,
+        }
+    }
+    // Check `block` against the state, including its state root,
+    // and compute its diff, without changing the state.
+    pub fn diff(&self, block: &Block) -> Result<StateDiff> {
+        let diff = self.transition(block)?;
+
+        if block.header.state_root != self.root_after(&diff) {
+            return Err(ChainError::InvalidStateRoot {
+                height: block.header.id,
+            });
+        }
+        Ok(diff)
+    }
+    // The state root that `block` must have, given its transactions.
+    pub fn state_root(&self, block: &Block) -> Result<String> {
+        Ok(self.root_after(&self.transition(block)?))
+    }
+    // Check that `transactions` can be included, in this order, in the
+    // block at `height` on top of this state.
+    pub fn check_transactions(&self, height: u64, transactions: Vec<Transaction>) -> Result<()> {
+        let block = Block::new(height, String::new(), transactions, 0);
+
+        self.transition(&block).map(|_| ())
+    }
+    // Diffs of another kind of ledger are ignored.
+    pub fn apply(&mut self, diff: &StateDiff) {
+        match (self, diff) {
+            (Self::Utxo(utxos), StateDiff::Utxo(diff)) => utxos.apply(diff),
+            (Self::Account(accounts), StateDiff::Account(diff)) => accounts.apply(diff),
+            _ => {}
+        }
+    }
+    pub fn revert(&mut self, diff: &StateDiff) {
+        match (self, diff) {
+            (Self::Utxo(utxos), StateDiff::Utxo(diff)) => utxos.revert(diff),
+            (Self::Account(accounts), StateDiff::Account(diff)) => accounts.revert(diff),
+            _ => {}
+        }
+    }
+    fn transition(&self, block: &Block) -> Result<StateDiff> {
+        match self {
+            Self::Utxo(utxos) => utxos.diff(block).map(StateDiff::Utxo),
+            Self::Account(accounts) => accounts.diff(block).map(StateDiff::Account),
+        }
+    }
+    // Only the account model commits to its state in the headers,
+    // the state root of the utxo model is always empty.
+    fn root_after(&self, diff: &StateDiff) -> String {
+        match (self, diff) {
+            (Self::Account(accounts), StateDiff::Account(diff)) => accounts.root_after(diff),
+            _ => String::new(),
+        }
+    }
+}
+
+// The ledger is persisted as a journal with the diff of every block
+// of the chain, one frame per height, with the same framing as the
+// block log. Connecting a block appends its diff, disconnecting it
+// reverts the last diff and truncates it, and opening the journal
+// applies every diff. It lives next to the block log, which is the
+// source of truth: `Blockchain` brings the journal back in sync with
+// the block log if a crash left them apart.
+pub struct StateStore {
+    file: File,
+    // offset and size of the frame of every diff, by height.
+    frames: Vec<(u64, usize)>,
+    size: u64,
+    ledger: Ledger,
+}
+
+impl StateStore {
+    pub async fn open(path: impl AsRef<Path>, kind: LedgerKind) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .await?;
+
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).await?;
+
+        let mut store = Self {
+            file,
+            frames: Vec::new(),
+            size: 0,
+            ledger: Ledger::new(kind),
+        };
+
+        while let Some((diff, len)) = decode_frame::<StateDiff>(&buf[store.size as usize..]) {
+            if diff.kind() != kind {
+                break;
+            }
+            store.ledger.apply(&diff);
+            store.frames.push((store.size, len));
+            store.size += len as u64;
+        }
+
+        if store.size < buf.len() as u64 {
+            warn!(
+                "{} ends with {} bytes of a torn write or of another ledger, truncating it.",
+                path.display(),
+                buf.len() as u64 - store.size
+            );
+            store.file.set_len(store.size).await?;
+        }
+        info!("opened the {kind:?} ledger at height {}.", store.len());
+
+        Ok(store)
+    }
+    // number of blocks applied to the ledger.
+    pub fn len(&self) -> u64 {
+        self.frames.len() as u64
+    }
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+    // The diff of the block at `height`.
+    pub async fn diff_at(&mut self, height: u64) -> io::Result<Option<StateDiff>> {
+        let (offset, len) = match self.frames.get(height as usize) {
+            Some(frame) => *frame,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0; len];
+
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.read_exact(&mut buf).await?;
+
+        decode_frame(&buf)
+            .map(|(diff, _)| Some(diff))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupted state diff at offset {offset}"),
+                )
+            })
+    }
+    // Apply the diff of the next block.
+    pub async fn push(&mut self, diff: StateDiff) -> io::Result<()> {
+        let frame = encode_frame(&diff)?;
+
+        self.file.write_all(&frame).await?;
+        self.file.sync_data().await?;
+
+        self.ledger.apply(&diff);
+        self.frames.push((self.size, frame.len()));
+        self.size += frame.len() as u64;
+
+        Ok(())
+    }
+    // Revert every block from `len` onwards.
+    pub async fn truncate(&mut self, len: u64) -> io::Result<()> {
+        for height in (len..self.len()).rev() {
+            if let Some(diff) = self.diff_at(height).await? {
+                self.ledger.revert(&diff);
+            }
+        }
+        if let Some(&(offset, _)) = self.frames.get(len as usize) {
+            self.file.set_len(offset).await?;
+            self.frames.truncate(len as usize);
+            self.size = offset;
+        }
+
+        Ok(())
+    }
+}
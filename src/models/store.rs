@@ -0,0 +1,705 @@
+use super::{
+    block::Block,
+    index::{BlockIndex, Location},
+    pow::{self, U256},
+};
+use log::{info, warn};
+use speedy::{LittleEndian, Readable, Writable};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+
+// Blocks are kept in an append-only log split into segment files
+// ("blk00000.dat", "blk00001.dat", ...). Every segment starts with a
+// header with the format version, followed by a frame per block:
+//
+// | len: u32 LE | crc32 of len: u32 LE | crc32 of payload: u32 LE | payload: speedy encoded block |
+//
+// Appending a block writes a single frame at the end of the last segment,
+// so a crash in the middle of a write can only leave a torn frame at the
+// very end of the log, which is detected and truncated on the next open:
+// either its header is incomplete, or it is valid but its payload is cut
+// short by the end of the segment. Any other frame that can't be read is
+// an error: the log is the source of truth, so a segment of another format
+// or a corrupted one is never cut. A segment whose header itself was torn
+// when it was created holds no frame yet, and is created again.
+//
+// Next to the segments lives "index.dat", which maps every height to the
+// location of its frame, and every hash to its height (see `BlockIndex`).
+const SEGMENT_HEADER: &[u8; 8] = b"BLKLOG02";
+const SEGMENT_MAX_SIZE: u64 = 32 * 1024 * 1024;
+const FRAME_HEADER_LEN: usize = 12;
+const INDEX_FILE: &str = "index.dat";
+
+pub struct BlockStore {
+    dir: PathBuf,
+    // id of the segment that is currently being appended to.
+    segment: u32,
+    file: File,
+    // size in bytes of the current segment.
+    size: u64,
+    index: BlockIndex,
+    tip: Option<Block>,
+}
+
+impl BlockStore {
+    // Open the log in `dir`, creating it if it doesn't exist yet.
+    // Only the last segment is scanned, earlier segments are sealed.
+    pub async fn open(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).await?;
+
+        let mut segments = list_segments(&dir).await?;
+        let mut segment = 0;
+        let mut size = SEGMENT_HEADER.len() as u64;
+        let mut tip = None;
+
+        while let Some(&last) = segments.last() {
+            let path = segment_path(&dir, last);
+            let buf = fs::read(&path).await?;
+
+            if buf.len() < SEGMENT_HEADER.len() && SEGMENT_HEADER.starts_with(&buf) {
+                warn!(
+                    "segment {} has a torn header, creating it again.",
+                    path.display()
+                );
+                fs::remove_file(&path).await?;
+                segments.pop();
+                continue;
+            }
+            let body = segment_body(&path, &buf)?;
+            let (frames, end) = decode_segment(&path, body)?;
+
+            if end < body.len() {
+                warn!(
+                    "segment {} ends with {} bytes of a torn write, truncating it.",
+                    path.display(),
+                    body.len() - end
+                );
+                let len = (SEGMENT_HEADER.len() + end) as u64;
+                open_segment(&dir, last).await?.set_len(len).await?;
+            }
+
+            // the torn write was the only frame of this segment,
+            // so the previous segment is the real end of the log.
+            if frames.is_empty() && segments.len() > 1 {
+                fs::remove_file(&path).await?;
+                segments.pop();
+                continue;
+            }
+
+            segment = last;
+            size = (SEGMENT_HEADER.len() + end) as u64;
+            tip = frames.into_iter().last().map(|(block, _, _)| block);
+            break;
+        }
+
+        let len = tip.as_ref().map_or(0, |block| block.header.id + 1);
+        let index = BlockIndex::open(dir.join(INDEX_FILE)).await?;
+
+        let mut store = Self {
+            file: open_segment(&dir, segment).await?,
+            dir,
+            segment,
+            size,
+            index,
+            tip,
+        };
+
+        if store.index.len() > len {
+            warn!("the index is ahead of the block log, truncating it.");
+            store.index.truncate(len).await?;
+        }
+        if store.index.len() < len {
+            store.reindex().await?;
+        }
+
+        info!("opened block log with {len} blocks.");
+
+        Ok(store)
+    }
+    pub fn len(&self) -> u64 {
+        self.index.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+    // The latest block of the chain.
+    pub fn tip(&self) -> Option<&Block> {
+        self.tip.as_ref()
+    }
+    // Height of the block with the given hash, without touching the log.
+    pub fn height_of(&self, hash: &str) -> Option<u64> {
+        self.index.height(hash)
+    }
+    // Hash of the block at `height`, without touching the log.
+    pub fn hash_at(&self, height: u64) -> Option<&str> {
+        self.index.hash(height)
+    }
+    // Accumulated work of the chain up to and including `height`.
+    pub fn work_at(&self, height: u64) -> Option<U256> {
+        self.index.work(height)
+    }
+    // Accumulated work of the whole chain.
+    pub fn work(&self) -> U256 {
+        self.len()
+            .checked_sub(1)
+            .and_then(|height| self.index.work(height))
+            .unwrap_or_default()
+    }
+    pub async fn get_block_by_height(&self, height: u64) -> io::Result<Option<Block>> {
+        match self.index.location(height) {
+            Some(location) => self.read_at(location).await.map(Some),
+            None => Ok(None),
+        }
+    }
+    pub async fn get_block_by_hash(&self, hash: &str) -> io::Result<Option<Block>> {
+        match self.index.height(hash) {
+            Some(height) => self.get_block_by_height(height).await,
+            None => Ok(None),
+        }
+    }
+    // Append a single block to the end of the log,
+    // rolling over to a new segment when the current one is full.
+    pub async fn append(&mut self, block: &Block) -> io::Result<()> {
+        let frame = encode_frame(block)?;
+
+        let empty = self.size == SEGMENT_HEADER.len() as u64;
+        if !empty && self.size + frame.len() as u64 > SEGMENT_MAX_SIZE {
+            self.segment += 1;
+            self.file = open_segment(&self.dir, self.segment).await?;
+            self.size = SEGMENT_HEADER.len() as u64;
+        }
+
+        self.file.write_all(&frame).await?;
+        self.file.sync_data().await?;
+
+        let location = Location {
+            segment: self.segment,
+            offset: self.size,
+            len: frame.len() as u32,
+        };
+        let work = self.work() + pow::work(block.header.bits);
+        self.index.push(location, work, &block.hash).await?;
+
+        self.size += frame.len() as u64;
+        self.tip = Some(block.clone());
+
+        Ok(())
+    }
+    // Drop every block from `len` onwards. The later segments are
+    // removed first, so that a crash in the middle leaves a valid log.
+    pub async fn truncate(&mut self, len: u64) -> io::Result<()> {
+        let location = match self.index.location(len) {
+            Some(location) => location,
+            None => return Ok(()),
+        };
+
+        for segment in list_segments(&self.dir).await?.into_iter().rev() {
+            if segment <= location.segment {
+                break;
+            }
+            fs::remove_file(segment_path(&self.dir, segment)).await?;
+        }
+
+        self.file = open_segment(&self.dir, location.segment).await?;
+        self.file.set_len(location.offset).await?;
+        self.segment = location.segment;
+        self.size = location.offset;
+
+        self.index.truncate(len).await?;
+
+        self.tip = match len.checked_sub(1) {
+            Some(height) => self.get_block_by_height(height).await?,
+            None => None,
+        };
+
+        Ok(())
+    }
+    // Read every block of the log, in order.
+    pub async fn read_all(&self) -> io::Result<Vec<Block>> {
+        let mut chain = Vec::with_capacity(self.len() as usize);
+
+        for segment in list_segments(&self.dir).await? {
+            let path = segment_path(&self.dir, segment);
+            let buf = fs::read(&path).await?;
+            let body = segment_body(&path, &buf)?;
+            let (frames, end) = decode_segment(&path, body)?;
+
+            if end < body.len() {
+                return Err(corrupted(&path, (SEGMENT_HEADER.len() + end) as u64));
+            }
+            chain.extend(frames.into_iter().map(|(block, _, _)| block));
+        }
+
+        Ok(chain)
+    }
+    async fn read_at(&self, location: Location) -> io::Result<Block> {
+        let path = segment_path(&self.dir, location.segment);
+        let mut file = File::open(&path).await?;
+        let mut buf = vec![0; location.len as usize];
+
+        file.seek(SeekFrom::Start(location.offset)).await?;
+        file.read_exact(&mut buf).await?;
+
+        read_frame(&buf)
+            .map_err(|_| corrupted(&path, location.offset))?
+            .map(|(block, _)| block)
+            .ok_or_else(|| corrupted(&path, location.offset))
+    }
+    // Add the blocks that are in the log but not in the index yet,
+    // starting right after the last indexed frame.
+    async fn reindex(&mut self) -> io::Result<()> {
+        let (first_segment, mut offset) = match self.index.last() {
+            Some(location) => (location.segment, location.end()),
+            None => (0, SEGMENT_HEADER.len() as u64),
+        };
+        info!("rebuilding the block index from height {}.", self.len());
+
+        for segment in list_segments(&self.dir).await? {
+            if segment < first_segment {
+                continue;
+            }
+            let path = segment_path(&self.dir, segment);
+            let buf = fs::read(&path).await?;
+            segment_body(&path, &buf)?;
+            let (frames, end) =
+                decode_segment(&path, buf.get(offset as usize..).unwrap_or_default())?;
+
+            if offset as usize + end < buf.len() {
+                return Err(corrupted(&path, offset + end as u64));
+            }
+            for (block, frame_offset, len) in frames {
+                let location = Location {
+                    segment,
+                    offset: offset + frame_offset as u64,
+                    len: len as u32,
+                };
+                let work = self.work() + pow::work(block.header.bits);
+                self.index.push(location, work, &block.hash).await?;
+            }
+            offset = SEGMENT_HEADER.len() as u64;
+        }
+
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+    dir.join(format!("blk{:05}.dat", segment))
+}
+
+fn corrupted(path: &Path, offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupted frame in {} at offset {offset}", path.display()),
+    )
+}
+
+// Open a segment for appending, a new one starts with the header.
+async fn open_segment(dir: &Path, segment: u32) -> io::Result<File> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(segment_path(dir, segment))
+        .await?;
+
+    if file.metadata().await?.len() == 0 {
+        file.write_all(SEGMENT_HEADER).await?;
+        file.sync_data().await?;
+    }
+    Ok(file)
+}
+
+// The frames of a segment, after its header. A segment of another format,
+// e.g. written by an older version, is an error rather than a torn write.
+fn segment_body<'a>(path: &Path, buf: &'a [u8]) -> io::Result<&'a [u8]> {
+    match buf.strip_prefix(SEGMENT_HEADER) {
+        Some(body) => Ok(body),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} is not a block log segment of a known format",
+                path.display()
+            ),
+        )),
+    }
+}
+
+// ids of all the segments in `dir`, sorted.
+async fn list_segments(dir: &Path) -> io::Result<Vec<u32>> {
+    let mut segments = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let id = name
+            .to_str()
+            .and_then(|name| name.strip_prefix("blk"))
+            .and_then(|name| name.strip_suffix(".dat"))
+            .and_then(|id| id.parse::<u32>().ok());
+
+        if let Some(id) = id {
+            segments.push(id);
+        }
+    }
+    segments.sort_unstable();
+
+    Ok(segments)
+}
+
+pub fn encode_frame<T: Writable<LittleEndian>>(value: &T) -> io::Result<Vec<u8>> {
+    let payload = value
+        .write_to_vec()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let len = (payload.len() as u32).to_le_bytes();
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len);
+    frame.extend_from_slice(&crc32fast::hash(&len).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+
+    Ok(frame)
+}
+
+// Decode the frame at the start of `buf`, returning the value and the
+// size of the whole frame. `None` if it is a torn write: its header is
+// incomplete, or its header is valid but its payload is cut short by the
+// end of `buf`. An error if it is corrupted, or its payload can't be decoded.
+pub fn read_frame<T: for<'a> Readable<'a, LittleEndian>>(
+    buf: &[u8],
+) -> io::Result<Option<(T, usize)>> {
+    let header = match buf.get(..FRAME_HEADER_LEN) {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let len = &header[0..4];
+    let len_checksum = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let checksum = u32::from_le_bytes(header[8..12].try_into().unwrap());
+
+    if crc32fast::hash(len) != len_checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame with a corrupted header",
+        ));
+    }
+    let end = FRAME_HEADER_LEN + u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    let payload = match buf.get(FRAME_HEADER_LEN..end) {
+        Some(payload) => payload,
+        None => return Ok(None),
+    };
+
+    if crc32fast::hash(payload) != checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "frame with a wrong checksum",
+        ));
+    }
+    let value =
+        T::read_from_buffer(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(Some((value, end)))
+}
+
+// Like `read_frame`, for logs that are derived from the block log and can
+// be rebuilt from it: any frame that can't be read ends them.
+pub fn decode_frame<T: for<'a> Readable<'a, LittleEndian>>(buf: &[u8]) -> Option<(T, usize)> {
+    read_frame(buf).ok().flatten()
+}
+
+// a block of a segment, with the offset and size of its frame.
+type Frame = (Block, usize, usize);
+
+// Decode all the frames of a segment, returning every block with the
+// offset and size of its frame, and the offset where the frames end,
+// before a torn write if any.
+fn decode_segment(path: &Path, buf: &[u8]) -> io::Result<(Vec<Frame>, usize)> {
+    let mut frames = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        match read_frame(&buf[offset..]) {
+            Ok(Some((block, len))) => {
+                frames.push((block, offset, len));
+                offset += len;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: frame at {offset}: {e}", path.display()),
+                ))
+            }
+        }
+    }
+
+    Ok((frames, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    static DIRS: AtomicU32 = AtomicU32::new(0);
+
+    // a fresh directory for a single test.
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "blockstore-{}-{}",
+            std::process::id(),
+            DIRS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn chain(len: u64) -> Vec<Block> {
+        let mut chain: Vec<Block> = Vec::new();
+        for id in 0..len {
+            let previous_hash = chain.last().map_or_else(String::new, |b| b.hash.clone());
+            let mut block = Block::new(id, previous_hash, Vec::new(), 0x2000ffff);
+            block.hash = block.calculate_hash();
+            chain.push(block);
+        }
+        chain
+    }
+
+    async fn store_with(dir: &Path, blocks: &[Block]) -> BlockStore {
+        let mut store = BlockStore::open(dir).await.unwrap();
+        for block in blocks {
+            store.append(block).await.unwrap();
+        }
+        store
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<String> {
+        blocks.iter().map(|block| block.hash.clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn reopen_keeps_every_block() {
+        let dir = temp_dir();
+        let blocks = chain(3);
+        drop(store_with(&dir, &blocks).await);
+
+        let store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.tip().unwrap().hash, blocks[2].hash);
+        assert_eq!(store.height_of(&blocks[1].hash), Some(1));
+        let block = store.get_block_by_height(1).await.unwrap().unwrap();
+        assert_eq!(block.hash, blocks[1].hash);
+        assert_eq!(hashes(&store.read_all().await.unwrap()), hashes(&blocks));
+    }
+
+    #[tokio::test]
+    async fn torn_frame_is_truncated() {
+        let dir = temp_dir();
+        let blocks = chain(4);
+        drop(store_with(&dir, &blocks[..3]).await);
+
+        let frame = encode_frame(&blocks[3]).unwrap();
+        let path = segment_path(&dir, 0);
+        let mut buf = std::fs::read(&path).unwrap();
+        let len = buf.len() as u64;
+        // both a torn header and a payload cut short are torn writes.
+        for torn in [&frame[..5], &frame[..frame.len() - 1]] {
+            std::fs::write(&path, [&buf[..], torn].concat()).unwrap();
+            let store = BlockStore::open(&dir).await.unwrap();
+            assert_eq!(store.len(), 3);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        }
+
+        let mut store = BlockStore::open(&dir).await.unwrap();
+        store.append(&blocks[3]).await.unwrap();
+        drop(store);
+        let store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(hashes(&store.read_all().await.unwrap()), hashes(&blocks));
+
+        buf.extend_from_slice(&frame);
+        assert_eq!(std::fs::read(&path).unwrap(), buf);
+    }
+
+    #[tokio::test]
+    async fn corrupted_frame_is_an_error() {
+        let dir = temp_dir();
+        let blocks = chain(4);
+        let store = store_with(&dir, &blocks).await;
+        let location = store.index.location(1).unwrap();
+        drop(store);
+
+        let path = segment_path(&dir, 0);
+        let buf = std::fs::read(&path).unwrap();
+        let offset = location.offset as usize;
+
+        // a length pointing past the end of the segment.
+        let mut bad_len = buf.clone();
+        bad_len[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        // a flipped bit in the payload.
+        let mut bad_payload = buf.clone();
+        bad_payload[offset + FRAME_HEADER_LEN] ^= 1;
+        // the same, in the last frame of the log.
+        let mut bad_last = buf.clone();
+        *bad_last.last_mut().unwrap() ^= 1;
+
+        for bad in [bad_len, bad_payload, bad_last] {
+            std::fs::write(&path, &bad).unwrap();
+            assert!(BlockStore::open(&dir).await.is_err());
+            // nothing was cut.
+            assert_eq!(std::fs::read(&path).unwrap(), bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn torn_segment_header_is_written_again() {
+        let dir = temp_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(segment_path(&dir, 0), b"BLK").unwrap();
+
+        let blocks = chain(1);
+        let store = store_with(&dir, &blocks).await;
+        let block = store.get_block_by_height(0).await.unwrap().unwrap();
+        assert_eq!(block.hash, blocks[0].hash);
+        drop(store);
+
+        let store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(hashes(&store.read_all().await.unwrap()), hashes(&blocks));
+    }
+
+    #[tokio::test]
+    async fn index_ahead_of_the_log_is_truncated() {
+        let dir = temp_dir();
+        let blocks = chain(3);
+        let store = store_with(&dir, &blocks).await;
+        let location = store.index.location(2).unwrap();
+        drop(store);
+
+        // the log lost its last block, but the index didn't.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(segment_path(&dir, 0))
+            .unwrap();
+        file.set_len(location.offset).unwrap();
+
+        let mut store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.tip().unwrap().hash, blocks[1].hash);
+        assert_eq!(store.height_of(&blocks[2].hash), None);
+
+        store.append(&blocks[2]).await.unwrap();
+        drop(store);
+        let store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.height_of(&blocks[2].hash), Some(2));
+    }
+
+    #[tokio::test]
+    async fn index_behind_the_log_is_rebuilt() {
+        let dir = temp_dir();
+        let blocks = chain(3);
+        drop(store_with(&dir, &blocks).await);
+
+        let index = dir.join(INDEX_FILE);
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&index)
+            .unwrap();
+        // keep the header and the first record only.
+        file.set_len(8 + 112).unwrap();
+        drop(file);
+        let store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.height_of(&blocks[2].hash), Some(2));
+        drop(store);
+
+        std::fs::remove_file(&index).unwrap();
+        let store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(
+            store.work(),
+            store.work_at(1).unwrap() + pow::work(0x2000ffff)
+        );
+        let block = store.get_block_by_hash(&blocks[1].hash).await.unwrap();
+        assert_eq!(block.unwrap().hash, blocks[1].hash);
+    }
+
+    #[tokio::test]
+    async fn full_segment_rolls_over() {
+        let dir = temp_dir();
+        let blocks = chain(4);
+        let mut store = store_with(&dir, &blocks[..2]).await;
+        // pretend the segment is full.
+        store.size = SEGMENT_MAX_SIZE;
+        store.append(&blocks[2]).await.unwrap();
+        store.append(&blocks[3]).await.unwrap();
+        assert_eq!(store.index.location(2).unwrap().segment, 1);
+        assert_eq!(store.index.location(3).unwrap().segment, 1);
+        drop(store);
+
+        std::fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+        let store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(list_segments(&dir).await.unwrap(), vec![0, 1]);
+        assert_eq!(hashes(&store.read_all().await.unwrap()), hashes(&blocks));
+        let block = store.get_block_by_height(3).await.unwrap().unwrap();
+        assert_eq!(block.hash, blocks[3].hash);
+    }
+
+    #[tokio::test]
+    async fn torn_first_frame_of_a_segment_removes_it() {
+        let dir = temp_dir();
+        let blocks = chain(3);
+        drop(store_with(&dir, &blocks[..2]).await);
+
+        let frame = encode_frame(&blocks[2]).unwrap();
+        let torn = [&SEGMENT_HEADER[..], &frame[..frame.len() / 2]].concat();
+        std::fs::write(segment_path(&dir, 1), torn).unwrap();
+
+        let mut store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(list_segments(&dir).await.unwrap(), vec![0]);
+        store.append(&blocks[2]).await.unwrap();
+        assert_eq!(store.index.location(2).unwrap().segment, 0);
+    }
+
+    #[tokio::test]
+    async fn truncate_drops_later_blocks_and_segments() {
+        let dir = temp_dir();
+        let blocks = chain(5);
+        let mut store = store_with(&dir, &blocks[..2]).await;
+        store.size = SEGMENT_MAX_SIZE;
+        for block in &blocks[2..] {
+            store.append(block).await.unwrap();
+        }
+
+        store.truncate(1).await.unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.tip().unwrap().hash, blocks[0].hash);
+        assert_eq!(store.height_of(&blocks[2].hash), None);
+        assert_eq!(list_segments(&dir).await.unwrap(), vec![0]);
+
+        // truncating past the end changes nothing.
+        store.truncate(10).await.unwrap();
+        assert_eq!(store.len(), 1);
+
+        store.append(&blocks[1]).await.unwrap();
+        store.append(&blocks[2]).await.unwrap();
+        drop(store);
+
+        let mut store = BlockStore::open(&dir).await.unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.tip().unwrap().hash, blocks[2].hash);
+
+        store.truncate(0).await.unwrap();
+        assert!(store.is_empty());
+        assert!(store.tip().is_none());
+        drop(store);
+        assert!(BlockStore::open(&dir).await.unwrap().is_empty());
+    }
+}
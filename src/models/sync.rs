@@ -0,0 +1,132 @@
+use super::block::{Block, BlockHeader};
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::upgrade,
+    request_response::{
+        ProtocolName, ProtocolSupport, RequestResponse, RequestResponseCodec,
+        RequestResponseConfig, RequestResponseEvent,
+    },
+};
+use speedy::{Readable, Writable};
+use std::{io, iter, time::Duration};
+
+const PROTOCOL: &[u8] = b"/blockchain/sync/1";
+// biggest request or response accepted, in bytes.
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+// how long a peer has to answer a request, it fails after that.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// most headers sent in a response.
+pub const MAX_HEADERS: u64 = 2000;
+// most blocks sent in a response, fewer are sent if they
+// would make the response bigger than `MAX_BLOCKS_SIZE` bytes.
+pub const MAX_BLOCKS: u64 = 128;
+pub const MAX_BLOCKS_SIZE: usize = MAX_MESSAGE_SIZE / 2;
+
+#[derive(Debug, Clone, Writable, Readable)]
+pub enum SyncRequest {
+    // a block of the canonical chain, by hash.
+    GetBlock { hash: String },
+    // the tip of the canonical chain.
+    GetTip,
+    // the headers of the canonical chain after the fork
+    // point with `locator`, see `Blockchain::locator`.
+    GetHeaders { locator: Vec<String> },
+    // blocks of the canonical chain, from height `from`.
+    GetBlocks { from: u64, count: u64 },
+}
+
+#[derive(Debug, Clone, Writable, Readable)]
+pub enum SyncResponse {
+    // `None` if the block is unknown.
+    Block(Option<Block>),
+    Tip { height: u64, hash: String },
+    // at most `MAX_HEADERS`, empty if there are none after the fork point.
+    Headers(Vec<BlockHeader>),
+    // at most `MAX_BLOCKS`.
+    Blocks(Vec<Block>),
+}
+
+// A request/response protocol to fetch blocks from a peer. Every request
+// opens a new substream, on which the peer writes its response. Requests
+// get an id, that their response or failure comes back with: a request
+// fails if the peer doesn't answer within `REQUEST_TIMEOUT`, if the
+// connection closes, or if the peer doesn't support the protocol.
+pub type SyncBehaviour = RequestResponse<SyncCodec>;
+pub type SyncEvent = RequestResponseEvent<SyncRequest, SyncResponse>;
+
+pub fn new_behaviour() -> SyncBehaviour {
+    let mut config = RequestResponseConfig::default();
+    config.set_request_timeout(REQUEST_TIMEOUT);
+
+    RequestResponse::new(
+        SyncCodec,
+        iter::once((SyncProtocol, ProtocolSupport::Full)),
+        config,
+    )
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        PROTOCOL
+    }
+}
+
+// Requests and responses are written with speedy, prefixed by their length.
+#[derive(Debug, Clone)]
+pub struct SyncCodec;
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read(io).await
+    }
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read(io).await
+    }
+    async fn write_request<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        request: SyncRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        upgrade::write_length_prefixed(io, request.write_to_vec().unwrap()).await
+    }
+    async fn write_response<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        response: SyncResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        upgrade::write_length_prefixed(io, response.write_to_vec().unwrap()).await
+    }
+}
+
+async fn read<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: for<'a> Readable<'a, speedy::LittleEndian>,
+{
+    let buf = upgrade::read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+
+    M::read_from_buffer(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
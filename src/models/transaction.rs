@@ -0,0 +1,126 @@
+use libp2p::identity::ed25519::{Keypair, PublicKey};
+use sha2::{Digest, Sha256};
+use speedy::{Readable, Writable};
+
+// A transaction spends outputs of earlier transactions that belong to
+// the sender, and creates new outputs. The value of the inputs must be
+// exactly the value of the outputs plus the fee.
+// Addresses are hex encoded ed25519 public keys.
+#[derive(Debug, Clone, PartialEq, Eq, Writable, Readable)]
+pub struct Transaction {
+    // encoded ed25519 public key of the sender, empty for the
+    // coins created by the genesis block and the coinbases.
+    pub sender: Vec<u8>,
+    pub inputs: Vec<OutPoint>,
+    pub outputs: Vec<Output>,
+    // number of transactions sent before this one by the sender,
+    // so that the same signed transaction can't be replayed, or
+    // the height of the block for a coinbase.
+    pub nonce: u64,
+    pub fee: u64,
+    // ed25519 signature of everything above.
+    pub signature: Vec<u8>,
+}
+
+// Reference to an output of a transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Writable, Readable)]
+pub struct OutPoint {
+    // hash of the transaction.
+    pub tx: String,
+    // position of the output in the transaction.
+    pub index: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Writable, Readable)]
+pub struct Output {
+    pub recipient: String,
+    pub amount: u64,
+}
+
+impl Transaction {
+    // Create a transaction signed with `keypair`.
+    pub fn new(
+        keypair: &Keypair,
+        inputs: Vec<OutPoint>,
+        outputs: Vec<Output>,
+        nonce: u64,
+        fee: u64,
+    ) -> Self {
+        let mut tx = Self {
+            sender: keypair.public().encode().to_vec(),
+            inputs,
+            outputs,
+            nonce,
+            fee,
+            signature: Vec::new(),
+        };
+        tx.signature = keypair.sign(&tx.signing_bytes());
+        tx
+    }
+    // Coins that are created out of thin air, without a sender or a
+    // signature. They are only valid in the genesis block, or as a coinbase.
+    pub fn mint(recipient: String, amount: u64) -> Self {
+        Self {
+            sender: Vec::new(),
+            inputs: Vec::new(),
+            outputs: vec![Output { recipient, amount }],
+            nonce: 0,
+            fee: 0,
+            signature: Vec::new(),
+        }
+    }
+    // The first transaction of every block but the genesis one, which pays
+    // the block reward to the miner. Its nonce is the height of the
+    // block, so that no two coinbases have the same hash.
+    pub fn coinbase(height: u64, recipient: String, amount: u64) -> Self {
+        Self {
+            nonce: height,
+            ..Self::mint(recipient, amount)
+        }
+    }
+    pub fn is_mint(&self) -> bool {
+        self.sender.is_empty()
+    }
+    // hex sha256 of the whole transaction, signature included.
+    pub fn hash(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.write_to_vec().unwrap());
+
+        format!("{:x}", hasher.finalize())
+    }
+    pub fn sender_address(&self) -> String {
+        hex::encode(&self.sender)
+    }
+    // Sum of the outputs, `None` on overflow.
+    pub fn output_value(&self) -> Option<u64> {
+        self.outputs
+            .iter()
+            .try_fold(0u64, |sum, output| sum.checked_add(output.amount))
+    }
+    // If the signature is valid for the sender's public key.
+    pub fn verify(&self) -> bool {
+        match PublicKey::decode(&self.sender) {
+            Ok(public) => public.verify(&self.signing_bytes(), &self.signature),
+            Err(_) => false,
+        }
+    }
+    // what the sender signs: the transaction without its signature.
+    fn signing_bytes(&self) -> Vec<u8> {
+        let mut tx = self.clone();
+        tx.signature = Vec::new();
+
+        tx.write_to_vec().unwrap()
+    }
+}
+
+pub fn address(public: &PublicKey) -> String {
+    hex::encode(public.encode())
+}
+
+// If `address` is a hex encoded ed25519 public key.
+pub fn is_valid_address(address: &str) -> bool {
+    hex::decode(address)
+        .ok()
+        .and_then(|bytes| PublicKey::decode(&bytes).ok())
+        .is_some()
+}
@@ -0,0 +1,162 @@
+use super::{block::Block, pow::U256};
+use std::collections::{HashMap, VecDeque};
+
+// how many orphans are kept before the oldest ones are evicted.
+const MAX_ORPHANS: usize = 256;
+
+// A block that is known to connect to the chain,
+// and the accumulated work of the branch up to it.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub block: Block,
+    pub work: U256,
+}
+
+// The canonical chain lives in the block store, the tree keeps
+// everything else in memory: the side branches that fork from the
+// canonical chain, and the orphans, whose parent is unknown yet.
+#[derive(Default)]
+pub struct BlockTree {
+    // blocks of side branches, by hash.
+    side: HashMap<String, Node>,
+    // orphans by hash, and the hashes of the orphans waiting for a parent.
+    orphans: HashMap<String, Block>,
+    children: HashMap<String, Vec<String>>,
+    // orphan hashes in arrival order, to evict the oldest ones.
+    arrivals: VecDeque<String>,
+}
+
+impl BlockTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn get(&self, hash: &str) -> Option<&Node> {
+        self.side.get(hash)
+    }
+    pub fn insert(&mut self, block: Block, work: U256) {
+        self.side.insert(block.hash.clone(), Node { block, work });
+    }
+    pub fn remove(&mut self, hash: &str) -> Option<Node> {
+        self.side.remove(hash)
+    }
+    pub fn is_orphan(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash)
+    }
+    pub fn orphans_len(&self) -> usize {
+        self.orphans.len()
+    }
+    pub fn add_orphan(&mut self, block: Block) {
+        if self.orphans.contains_key(&block.hash) {
+            return;
+        }
+        while self.orphans.len() >= MAX_ORPHANS {
+            match self.arrivals.pop_front() {
+                Some(hash) => self.remove_orphan(&hash),
+                None => break,
+            };
+        }
+
+        self.children
+            .entry(block.header.previous_hash.clone())
+            .or_default()
+            .push(block.hash.clone());
+        self.arrivals.push_back(block.hash.clone());
+        self.orphans.insert(block.hash.clone(), block);
+    }
+    // Remove and return the orphans whose parent is `parent`.
+    pub fn take_orphans(&mut self, parent: &str) -> Vec<Block> {
+        let hashes = self.children.remove(parent).unwrap_or_default();
+        self.arrivals.retain(|hash| !hashes.contains(hash));
+
+        hashes
+            .iter()
+            .filter_map(|hash| self.orphans.remove(hash))
+            .collect()
+    }
+    // Forget the side blocks below `height`, they are too deep
+    // to ever become part of the canonical chain again.
+    pub fn prune(&mut self, height: u64) {
+        self.side.retain(|_, node| node.block.header.id >= height);
+    }
+    fn remove_orphan(&mut self, hash: &str) -> Option<Block> {
+        let block = self.orphans.remove(hash)?;
+
+        if let Some(siblings) = self.children.get_mut(&block.header.previous_hash) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.children.remove(&block.header.previous_hash);
+            }
+        }
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a block with the given hash and parent, nothing else matters here.
+    fn block(id: u64, hash: &str, parent: &str) -> Block {
+        let mut block = Block::new(id, parent.to_owned(), Vec::new(), 0x2000ffff);
+        block.hash = hash.to_owned();
+        block
+    }
+
+    #[test]
+    fn orphans_are_taken_by_parent() {
+        let mut tree = BlockTree::new();
+        tree.add_orphan(block(2, "b", "a"));
+        tree.add_orphan(block(2, "c", "a"));
+        tree.add_orphan(block(3, "d", "b"));
+        tree.add_orphan(block(3, "d", "b"));
+        assert_eq!(tree.orphans_len(), 3);
+
+        let mut taken: Vec<_> = tree
+            .take_orphans("a")
+            .into_iter()
+            .map(|block| block.hash)
+            .collect();
+        taken.sort();
+        assert_eq!(taken, ["b", "c"]);
+        assert!(!tree.is_orphan("b"));
+        assert!(tree.take_orphans("a").is_empty());
+
+        assert_eq!(tree.take_orphans("b")[0].hash, "d");
+        assert_eq!(tree.orphans_len(), 0);
+    }
+
+    #[test]
+    fn oldest_orphans_are_evicted() {
+        let mut tree = BlockTree::new();
+        for i in 0..MAX_ORPHANS + 2 {
+            tree.add_orphan(block(1, &format!("orphan{i}"), &format!("parent{i}")));
+        }
+        assert_eq!(tree.orphans_len(), MAX_ORPHANS);
+        assert!(!tree.is_orphan("orphan0"));
+        assert!(!tree.is_orphan("orphan1"));
+        assert!(tree.is_orphan("orphan2"));
+        // the parents of evicted orphans don't keep them around.
+        assert!(tree.take_orphans("parent0").is_empty());
+        assert_eq!(tree.take_orphans("parent2").len(), 1);
+
+        // taking an orphan makes room for a new one.
+        tree.add_orphan(block(1, "new", "parent"));
+        assert_eq!(tree.orphans_len(), MAX_ORPHANS);
+        assert!(tree.is_orphan("orphan3"));
+    }
+
+    #[test]
+    fn prune_forgets_deep_side_blocks() {
+        let mut tree = BlockTree::new();
+        tree.insert(block(1, "a", "genesis"), U256::from(2));
+        tree.insert(block(2, "b", "a"), U256::from(3));
+        assert_eq!(tree.get("b").unwrap().work, U256::from(3));
+
+        tree.prune(2);
+        assert!(tree.get("a").is_none());
+        assert!(tree.get("b").is_some());
+
+        assert_eq!(tree.remove("b").unwrap().block.hash, "b");
+        assert!(tree.get("b").is_none());
+    }
+}
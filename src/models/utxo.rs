@@ -0,0 +1,251 @@
+use super::{
+    block::Block,
+    error::{ChainError, Result},
+    transaction::{OutPoint, Output},
+};
+use speedy::{Readable, Writable};
+use std::collections::{HashMap, HashSet};
+
+// The outputs that a block spent and created. It is all that is
+// needed to apply the block to the set, or to revert it.
+#[derive(Debug, Clone, Default, Writable, Readable)]
+pub struct UtxoDiff {
+    // hash of the block.
+    pub hash: String,
+    pub spent: Vec<(OutPoint, Output)>,
+    pub created: Vec<(OutPoint, Output)>,
+}
+
+// The outputs that are not spent yet.
+#[derive(Debug, Clone, Default)]
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, Output>,
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&Output> {
+        self.outputs.get(outpoint)
+    }
+    // The unspent outputs of `address`.
+    pub fn unspent(&self, address: &str) -> Vec<(OutPoint, Output)> {
+        self.outputs
+            .iter()
+            .filter(|(_, output)| output.recipient == address)
+            .map(|(outpoint, output)| (outpoint.clone(), output.clone()))
+            .collect()
+    }
+    pub fn balance(&self, address: &str) -> u64 {
+        self.outputs
+            .values()
+            .filter(|output| output.recipient == address)
+            .map(|output| output.amount)
+            .sum()
+    }
+    // Check the spends of `block` against the set and compute its diff,
+    // without changing the set. A transaction can spend the outputs of
+    // the transactions that come before it in the same block.
+    pub fn diff(&self, block: &Block) -> Result<UtxoDiff> {
+        let height = block.header.id;
+        let mut diff = UtxoDiff {
+            hash: block.hash.clone(),
+            ..Default::default()
+        };
+        // outputs created by this block, that are not spent yet.
+        let mut created = HashMap::new();
+        let mut spent = HashSet::new();
+
+        for (index, tx) in block.transactions.iter().enumerate() {
+            let hash = tx.hash();
+
+            if !block.mints(index) {
+                if tx.inputs.is_empty() {
+                    return Err(ChainError::InvalidSpend { height, tx: hash });
+                }
+                let sender = tx.sender_address();
+                let mut value: u64 = 0;
+
+                for input in &tx.inputs {
+                    let output = match created.remove(input) {
+                        Some(output) => Some(output),
+                        None if spent.contains(input) => None,
+                        None => self.outputs.get(input).cloned(),
+                    };
+                    let output = output.ok_or_else(|| ChainError::MissingOutput {
+                        height,
+                        tx: hash.clone(),
+                    })?;
+
+                    if output.recipient != sender {
+                        return Err(ChainError::InvalidSpend { height, tx: hash });
+                    }
+                    value = value.checked_add(output.amount).ok_or_else(|| {
+                        ChainError::UnbalancedTransaction {
+                            height,
+                            tx: hash.clone(),
+                        }
+                    })?;
+
+                    // an output created by this block never makes it to the set.
+                    if spent.insert(input.clone()) && self.outputs.contains_key(input) {
+                        diff.spent.push((input.clone(), output));
+                    }
+                }
+
+                let expected = tx.output_value().and_then(|sum| sum.checked_add(tx.fee));
+                if expected != Some(value) {
+                    return Err(ChainError::UnbalancedTransaction { height, tx: hash });
+                }
+            }
+
+            for (index, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint {
+                    tx: hash.clone(),
+                    index: index as u32,
+                };
+                created.insert(outpoint, output.clone());
+            }
+        }
+        diff.created = created.into_iter().collect();
+
+        Ok(diff)
+    }
+    pub fn apply(&mut self, diff: &UtxoDiff) {
+        for (outpoint, _) in &diff.spent {
+            self.outputs.remove(outpoint);
+        }
+        for (outpoint, output) in &diff.created {
+            self.outputs.insert(outpoint.clone(), output.clone());
+        }
+    }
+    pub fn revert(&mut self, diff: &UtxoDiff) {
+        for (outpoint, _) in &diff.created {
+            self.outputs.remove(outpoint);
+        }
+        for (outpoint, output) in &diff.spent {
+            self.outputs.insert(outpoint.clone(), output.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::transaction::{self, Transaction};
+    use libp2p::identity::ed25519::Keypair;
+
+    fn block(id: u64, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block::new(id, String::new(), transactions, 0x2000ffff);
+        block.hash = block.calculate_hash();
+        block
+    }
+
+    fn outpoint(tx: &Transaction, index: u32) -> OutPoint {
+        OutPoint {
+            tx: tx.hash(),
+            index,
+        }
+    }
+
+    fn pay(keypair: &Keypair, inputs: Vec<OutPoint>, recipient: &str, amount: u64) -> Transaction {
+        let output = Output {
+            recipient: recipient.to_owned(),
+            amount,
+        };
+        Transaction::new(keypair, inputs, vec![output], 0, 0)
+    }
+
+    // a set where `keypair` owns a single output of 100 coins, minted by `genesis`.
+    fn funded(keypair: &Keypair) -> (UtxoSet, Transaction) {
+        let sender = transaction::address(&keypair.public());
+        let mint = Transaction::mint(sender, 100);
+        let mut set = UtxoSet::new();
+        set.apply(&set.diff(&block(0, vec![mint.clone()])).unwrap());
+
+        (set, mint)
+    }
+
+    fn coinbase(height: u64) -> Transaction {
+        Transaction::coinbase(height, "aa".repeat(32), 50)
+    }
+
+    #[test]
+    fn spend_moves_the_output() {
+        let alice = Keypair::generate();
+        let (mut set, mint) = funded(&alice);
+        let bob = "bb".repeat(32);
+
+        let tx = pay(&alice, vec![outpoint(&mint, 0)], &bob, 100);
+        let diff = set.diff(&block(1, vec![coinbase(1), tx])).unwrap();
+        set.apply(&diff);
+        assert_eq!(set.balance(&transaction::address(&alice.public())), 0);
+        assert_eq!(set.balance(&bob), 100);
+        assert_eq!(set.len(), 2);
+
+        set.revert(&diff);
+        assert_eq!(set.balance(&transaction::address(&alice.public())), 100);
+        assert_eq!(set.balance(&bob), 0);
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn double_spend_within_a_block_is_refused() {
+        let alice = Keypair::generate();
+        let (set, mint) = funded(&alice);
+
+        let first = pay(&alice, vec![outpoint(&mint, 0)], &"bb".repeat(32), 100);
+        let second = pay(&alice, vec![outpoint(&mint, 0)], &"cc".repeat(32), 100);
+        let double = block(1, vec![coinbase(1), first, second]);
+        assert!(matches!(
+            set.diff(&double),
+            Err(ChainError::MissingOutput { height: 1, .. })
+        ));
+
+        // the same input twice in a single transaction.
+        let twice = pay(&alice, vec![outpoint(&mint, 0); 2], &"bb".repeat(32), 200);
+        assert!(set.diff(&block(1, vec![coinbase(1), twice])).is_err());
+
+        // spending an output of an earlier transaction of the block is
+        // fine, but only once.
+        let alice_address = transaction::address(&alice.public());
+        let change = pay(&alice, vec![outpoint(&mint, 0)], &alice_address, 100);
+        let spend = pay(&alice, vec![outpoint(&change, 0)], &"bb".repeat(32), 100);
+        let chained = block(1, vec![coinbase(1), change.clone(), spend.clone()]);
+        let diff = set.diff(&chained).unwrap();
+        assert_eq!(diff.spent.len(), 1);
+        assert!(!diff.created.iter().any(|(o, _)| *o == outpoint(&change, 0)));
+
+        let again = pay(&alice, vec![outpoint(&change, 0)], &"cc".repeat(32), 100);
+        let double = block(1, vec![coinbase(1), change, spend, again]);
+        assert!(set.diff(&double).is_err());
+    }
+
+    #[test]
+    fn double_spend_across_blocks_is_refused() {
+        let alice = Keypair::generate();
+        let (mut set, mint) = funded(&alice);
+
+        let first = pay(&alice, vec![outpoint(&mint, 0)], &"bb".repeat(32), 100);
+        let diff = set.diff(&block(1, vec![coinbase(1), first])).unwrap();
+        set.apply(&diff);
+
+        let second = pay(&alice, vec![outpoint(&mint, 0)], &"cc".repeat(32), 100);
+        let double = block(2, vec![coinbase(2), second]);
+        assert!(matches!(
+            set.diff(&double),
+            Err(ChainError::MissingOutput { height: 2, .. })
+        ));
+
+        // once the first spend is reverted, the output can be spent again.
+        set.revert(&diff);
+        assert!(set.diff(&double).is_ok());
+    }
+}
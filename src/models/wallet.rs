@@ -0,0 +1,175 @@
+use super::{
+    account::AccountState,
+    error::{ChainError, Result},
+    mempool::Mempool,
+    state::Ledger,
+    transaction::{self, Output, Transaction},
+    utxo::UtxoSet,
+};
+use libp2p::identity::ed25519::Keypair;
+use log::info;
+use std::{collections::HashSet, path::Path};
+use tokio::{
+    fs,
+    io::{self, AsyncWriteExt, ErrorKind},
+};
+
+// The key that signs the transactions sent from this node.
+pub struct Wallet {
+    keypair: Keypair,
+    // transactions sent from this node that the mempool accepted, in
+    // order. Their inputs and nonces are taken until they leave it.
+    pending: Vec<Transaction>,
+}
+
+impl Wallet {
+    pub fn new(keypair: Keypair) -> Self {
+        Self {
+            keypair,
+            pending: Vec::new(),
+        }
+    }
+    // Load the keypair stored in `path`, or generate
+    // a new one and store it there if there is none.
+    pub async fn load_or_generate(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+
+        let keypair = match fs::read(path).await {
+            Ok(mut bytes) => Keypair::decode(&mut bytes)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let keypair = Keypair::generate();
+
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).await?;
+                }
+                // only readable by us, it can spend our coins.
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                options.mode(0o600);
+                let mut file = options.open(path).await?;
+                file.write_all(&keypair.encode()).await?;
+                file.sync_all().await?;
+                info!("generated a new wallet in {}", path.display());
+
+                keypair
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self::new(keypair))
+    }
+    pub fn address(&self) -> String {
+        transaction::address(&self.keypair.public())
+    }
+    pub fn balance(&self, ledger: &Ledger) -> u64 {
+        ledger.balance(&self.address())
+    }
+    // Sign a new transaction that sends `amount` to `recipient`, on top of
+    // the ones we sent that are still in `mempool`. Nothing is taken until
+    // the transaction is accepted by the mempool, see `sent`.
+    pub fn send(
+        &mut self,
+        ledger: &Ledger,
+        mempool: &Mempool,
+        recipient: String,
+        amount: u64,
+        fee: u64,
+    ) -> Result<Transaction> {
+        let output = Output { recipient, amount };
+
+        // the ones that left it were included in a block, evicted or
+        // expired, their inputs and nonces are free or spent for good.
+        self.pending.retain(|tx| mempool.contains(&tx.hash()));
+
+        match ledger {
+            Ledger::Utxo(utxos) => self.spend_outputs(utxos, output, fee),
+            Ledger::Account(accounts) => self.spend_balance(accounts, output, fee),
+        }
+    }
+    // `tx`, made by `send`, was accepted by the mempool.
+    pub fn sent(&mut self, tx: Transaction) {
+        self.pending.push(tx);
+    }
+    // nonce of the next transaction, after the pending ones.
+    fn next_nonce(&self, confirmed: u64) -> u64 {
+        self.pending
+            .iter()
+            .map(|tx| tx.nonce + 1)
+            .fold(confirmed, u64::max)
+    }
+    // Spend the biggest outputs of the wallet first,
+    // the change goes back to us.
+    fn spend_outputs(&self, utxos: &UtxoSet, output: Output, fee: u64) -> Result<Transaction> {
+        let address = self.address();
+        let needed = output.amount.saturating_add(fee);
+
+        let reserved: HashSet<_> = self.pending.iter().flat_map(|tx| &tx.inputs).collect();
+
+        let mut unspent = utxos.unspent(&address);
+        unspent.retain(|(outpoint, _)| !reserved.contains(outpoint));
+        unspent.sort_by_key(|(_, output)| std::cmp::Reverse(output.amount));
+
+        let mut inputs = Vec::new();
+        let mut value: u64 = 0;
+        for (outpoint, output) in unspent {
+            if value >= needed {
+                break;
+            }
+            value = value.saturating_add(output.amount);
+            inputs.push(outpoint);
+        }
+        if value < needed {
+            return Err(ChainError::InsufficientFunds {
+                needed,
+                available: value,
+            });
+        }
+
+        let mut outputs = vec![output];
+        if value > needed {
+            outputs.push(Output {
+                recipient: address,
+                amount: value - needed,
+            });
+        }
+
+        Ok(Transaction::new(
+            &self.keypair,
+            inputs,
+            outputs,
+            self.next_nonce(0),
+            fee,
+        ))
+    }
+    // Spend from the balance of our account, minus what the
+    // transactions that are not in the chain yet spend.
+    fn spend_balance(
+        &self,
+        accounts: &AccountState,
+        output: Output,
+        fee: u64,
+    ) -> Result<Transaction> {
+        let account = accounts.get(&self.address());
+        let needed = output.amount.saturating_add(fee);
+
+        let pending = self.pending.iter().fold(0u64, |sum, tx| {
+            sum.saturating_add(tx.output_value().unwrap_or(u64::MAX))
+                .saturating_add(tx.fee)
+        });
+        let available = account.balance.saturating_sub(pending);
+
+        if available < needed {
+            return Err(ChainError::InsufficientFunds { needed, available });
+        }
+
+        Ok(Transaction::new(
+            &self.keypair,
+            Vec::new(),
+            vec![output],
+            self.next_nonce(account.nonce),
+            fee,
+        ))
+    }
+}